@@ -0,0 +1,15 @@
+-- The AM and PM peak hour of each location and day, stored alongside its daily totals (see "Peak
+-- hours" in the README). Every insert into TBLHEADER sets these columns, so this must be run
+-- before deploying a version of the program that writes them.
+alter table TBLHEADER add (
+    AMPEAKTIME  date,
+    AMPEAKTOTAL number(10),
+    AMPEAKPED   number(10),
+    AMPEAKBIKE  number(10),
+    AMPHF       number(5, 4),
+    PMPEAKTIME  date,
+    PMPEAKTOTAL number(10),
+    PMPEAKPED   number(10),
+    PMPEAKBIKE  number(10),
+    PMPHF       number(5, 4)
+);
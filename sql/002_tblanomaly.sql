@@ -0,0 +1,12 @@
+-- Daily totals flagged as deviating from their location's historical baseline (see "Anomaly
+-- detection" in the README).
+create table TBLANOMALY (
+    LOCATIONID     number(10) not null,
+    COUNTDATE      date not null,
+    TOTAL          number(10) not null,
+    BASELINEMEDIAN number not null,
+    BASELINEMAD    number not null,
+    ROBUSTZ        number not null
+);
+
+create index TBLANOMALY_COUNTDATE on TBLANOMALY (COUNTDATE);
@@ -0,0 +1,13 @@
+-- The weather and holiday of each location and day (see "Weather and holiday annotation" in the
+-- README). Weather values are in the units the NOAA file was downloaded in.
+create table TBLANNOTATION (
+    LOCATIONID    number(10) not null,
+    COUNTDATE     date not null,
+    PRECIPITATION number,
+    TMAX          number,
+    TMIN          number,
+    HOLIDAY       varchar2(100),
+    DAYTYPE       varchar2(10) not null
+);
+
+create index TBLANNOTATION_COUNTDATE on TBLANNOTATION (COUNTDATE);
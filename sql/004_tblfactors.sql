@@ -0,0 +1,9 @@
+-- Monthly and day-of-week expansion factors, for each location (with a null FACTORGROUP) and each
+-- factor group (with a null LOCATIONID). See "Expansion factors" in the README.
+create table TBLFACTORS (
+    FACTORGROUP varchar2(100),
+    LOCATIONID  number(10),
+    FACTORTYPE  varchar2(5) not null,
+    PERIOD      number(2) not null,
+    FACTOR      number not null
+);
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+
+use crate::parser::IndividualCount;
+use crate::peak::{self, PeakHour};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregatedCount {
+    pub location_id: i32,
+    pub date: NaiveDate,
+    pub total_ped: Option<i32>,
+    pub total_bike: Option<i32>,
+    pub total: Option<i32>,
+    pub am_peak: Option<PeakHour>,
+    pub pm_peak: Option<PeakHour>,
+}
+
+impl AggregatedCount {
+    pub fn new(
+        location_id: i32,
+        date: NaiveDate,
+        total_ped: Option<i32>,
+        total_bike: Option<i32>,
+        total: Option<i32>,
+        am_peak: Option<PeakHour>,
+        pm_peak: Option<PeakHour>,
+    ) -> Self {
+        Self {
+            location_id,
+            date,
+            total_ped,
+            total_bike,
+            total,
+            am_peak,
+            pm_peak,
+        }
+    }
+}
+
+// Sum individual counts by date and location, with the AM/PM peak hour of each.
+pub fn aggregate(counts: &[IndividualCount]) -> Vec<AggregatedCount> {
+    let mut daily_counts = HashMap::new();
+
+    for count in counts {
+        let date = count.datetime.date();
+
+        // running_xxx are the running totals that are (possibly) updated on each loop
+        let (running_ped, running_bike, running_total) = daily_counts
+            .entry((count.location_id, date))
+            .or_insert((None, None, None));
+
+        // sum ped in/out
+        let mut ped_total = None;
+
+        if let Some(v) = count.ped_in {
+            ped_total = Some(v);
+        }
+        if let Some(v) = count.ped_out {
+            if let Some(w) = ped_total {
+                ped_total = Some(w + v)
+            } else {
+                ped_total = Some(v)
+            }
+        }
+        // now add it to our running sum
+        if let Some(v) = ped_total {
+            if let Some(w) = running_ped {
+                *w += v
+            } else {
+                *running_ped = Some(v)
+            }
+        }
+
+        // sum bike in/out
+        let mut bike_total = None;
+
+        if let Some(v) = count.bike_in {
+            bike_total = Some(v);
+        }
+        if let Some(v) = count.bike_out {
+            if let Some(w) = bike_total {
+                bike_total = Some(w + v)
+            } else {
+                bike_total = Some(v)
+            }
+        }
+        // now add it to our running sum
+        if let Some(v) = bike_total {
+            if let Some(w) = running_bike {
+                *w += v
+            } else {
+                *running_bike = Some(v)
+            }
+        }
+
+        // sum total
+        let mut total: Option<i32> = None;
+
+        if let Some(v) = count.total {
+            total = Some(v);
+        }
+        // add total to running total
+        if let Some(v) = total {
+            if let Some(w) = running_total {
+                *w += v
+            } else {
+                *running_total = Some(v)
+            }
+        }
+    }
+
+    // Find the AM/PM peak hour for each date/location_id, to be stored alongside daily totals.
+    let mut peaks = peak::peak_hours(counts);
+
+    // Flatten that hashmap into a vec.
+    let mut flattened_daily_counts = vec![];
+    for ((location_id, date), (ped_total, bike_total, total)) in daily_counts {
+        let (am_peak, pm_peak) = peaks.remove(&(location_id, date)).unwrap_or((None, None));
+        flattened_daily_counts.push(AggregatedCount::new(
+            location_id,
+            date,
+            ped_total,
+            bike_total,
+            total,
+            am_peak,
+            pm_peak,
+        ));
+    }
+
+    flattened_daily_counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(hour: u32, values: [Option<i32>; 5]) -> IndividualCount {
+        let [total, ped_in, ped_out, bike_in, bike_out] = values;
+        IndividualCount {
+            location_id: 16,
+            datetime: NaiveDate::from_ymd_opt(2024, 6, 1)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap(),
+            total,
+            ped_in,
+            ped_out,
+            bike_in,
+            bike_out,
+        }
+    }
+
+    #[test]
+    fn sums_each_mode_by_day_and_location() {
+        let counts = [
+            count(8, [Some(10), Some(1), Some(2), Some(3), Some(4)]),
+            count(9, [Some(20), Some(5), Some(5), Some(5), Some(5)]),
+        ];
+        let daily = aggregate(&counts);
+        assert_eq!(daily.len(), 1);
+        let day = &daily[0];
+        assert_eq!(day.date, NaiveDate::from_ymd_opt(2024, 6, 1).unwrap());
+        assert_eq!(
+            (day.total_ped, day.total_bike, day.total),
+            (Some(13), Some(17), Some(30))
+        );
+    }
+
+    #[test]
+    fn missing_values_are_skipped_not_zero() {
+        let counts = [
+            count(8, [None, Some(1), None, None, None]),
+            count(9, [Some(20), None, Some(2), None, None]),
+            count(10, [None, None, None, None, None]),
+        ];
+        let day = &aggregate(&counts)[0];
+        assert_eq!(
+            (day.total_ped, day.total_bike, day.total),
+            (Some(3), None, Some(20))
+        );
+    }
+
+    #[test]
+    fn all_missing_is_none() {
+        let counts = [
+            count(8, [None, None, None, None, None]),
+            count(9, [None, None, None, None, None]),
+        ];
+        let day = &aggregate(&counts)[0];
+        assert_eq!(
+            (day.total_ped, day.total_bike, day.total),
+            (None, None, None)
+        );
+        assert!(day.am_peak.is_none() && day.pm_peak.is_none());
+    }
+
+    #[test]
+    fn days_and_locations_are_separate() {
+        let mut other_location = count(8, [Some(5), None, None, None, None]);
+        other_location.location_id = 1;
+        let mut next_day = count(8, [Some(7), None, None, None, None]);
+        next_day.datetime += chrono::Duration::days(1);
+        let counts = [
+            count(8, [Some(3), None, None, None, None]),
+            other_location,
+            next_day,
+        ];
+        let mut totals = aggregate(&counts)
+            .iter()
+            .map(|c| (c.location_id, c.date.day(), c.total))
+            .collect::<Vec<_>>();
+        totals.sort();
+        assert_eq!(
+            totals,
+            [(1, 1, Some(5)), (16, 1, Some(3)), (16, 2, Some(7))]
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::prelude::*;
+use log::warn;
+use oracle::{Connection, Error as OracleError};
+
+use crate::aggregation::AggregatedCount;
+use crate::db::to_timestamp;
+use crate::geojson::Location;
+use crate::report::TableCounts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DayType {
+    Weekday,
+    Weekend,
+    Holiday,
+}
+
+impl fmt::Display for DayType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DayType::Weekday => write!(f, "weekday"),
+            DayType::Weekend => write!(f, "weekend"),
+            DayType::Holiday => write!(f, "holiday"),
+        }
+    }
+}
+
+// Weather for a day, in whatever units the weather file was downloaded in.
+#[derive(Debug, Clone, Default)]
+pub struct Weather {
+    pub precipitation: Option<f64>,
+    pub tmax: Option<f64>,
+    pub tmin: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub location_id: i32,
+    pub date: NaiveDate,
+    pub weather: Weather,
+    pub holiday: Option<String>,
+    pub day_type: DayType,
+}
+
+#[derive(Debug)]
+pub enum AnnotationError {
+    Csv(csv::Error),
+    MissingColumn(&'static str),
+    Date(String),
+}
+
+impl fmt::Display for AnnotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnotationError::Csv(e) => write!(f, "{e}"),
+            AnnotationError::MissingColumn(c) => write!(f, "Expected column {c} not found."),
+            AnnotationError::Date(d) => write!(f, "Could not parse date ({d})."),
+        }
+    }
+}
+
+impl From<csv::Error> for AnnotationError {
+    fn from(e: csv::Error) -> Self {
+        AnnotationError::Csv(e)
+    }
+}
+
+// Daily weather from each station in a NOAA file, and the stations nearest each location.
+#[derive(Debug, Default)]
+pub struct Stations {
+    // Each station's weather by day, keyed by its id.
+    weather: HashMap<String, HashMap<NaiveDate, Weather>>,
+    // The stations with coordinates, nearest first, for each location with coordinates.
+    nearest: HashMap<i32, Vec<String>>,
+}
+
+impl Stations {
+    // The weather at `location_id` on `date`. Each value is taken from the nearest station that
+    // reported it that day, as not every station reports every element. A location without
+    // coordinates, or a file without them, gets the average of all stations instead.
+    pub fn get(&self, location_id: i32, date: NaiveDate) -> Weather {
+        let Some(nearest) = self.nearest.get(&location_id) else {
+            return self.average(date);
+        };
+        let days = nearest
+            .iter()
+            .filter_map(|s| self.weather.get(s).and_then(|days| days.get(&date)))
+            .collect::<Vec<_>>();
+        Weather {
+            precipitation: days.iter().find_map(|w| w.precipitation),
+            tmax: days.iter().find_map(|w| w.tmax),
+            tmin: days.iter().find_map(|w| w.tmin),
+        }
+    }
+
+    fn average(&self, date: NaiveDate) -> Weather {
+        let days = self
+            .weather
+            .values()
+            .filter_map(|days| days.get(&date))
+            .collect::<Vec<_>>();
+        let average = |value: fn(&Weather) -> Option<f64>| {
+            let values = days.iter().filter_map(|w| value(w)).collect::<Vec<_>>();
+            if values.is_empty() {
+                None
+            } else {
+                Some(values.iter().sum::<f64>() / values.len() as f64)
+            }
+        };
+        Weather {
+            precipitation: average(|w| w.precipitation),
+            tmax: average(|w| w.tmax),
+            tmin: average(|w| w.tmin),
+        }
+    }
+}
+
+// Read daily weather from a CSV of NOAA daily summaries (columns STATION, LATITUDE, LONGITUDE,
+// DATE, PRCP, TMAX, TMIN, as downloaded from the Climate Data Online search with "Geographic
+// location" included), and find the stations nearest each of `locations`.
+pub fn read_weather(path: &str, locations: &[Location]) -> Result<Stations, AnnotationError> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    let column = |name: &'static str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .ok_or(AnnotationError::MissingColumn(name))
+    };
+    let date_col = column("DATE")?;
+    // A file for a single station may not have these, so its weather applies everywhere.
+    let station_col = column("STATION").ok();
+    let latitude_col = column("LATITUDE").ok();
+    let longitude_col = column("LONGITUDE").ok();
+    // Not every station reports every element, so these are optional.
+    let prcp_col = column("PRCP").ok();
+    let tmax_col = column("TMAX").ok();
+    let tmin_col = column("TMIN").ok();
+
+    let mut stations = Stations::default();
+    let mut coordinates: HashMap<String, (f64, f64)> = HashMap::new();
+    for result in rdr.records() {
+        let record = result?;
+        let value = |col: Option<usize>| {
+            col.and_then(|c| record.get(c))
+                .and_then(|v| v.trim().parse::<f64>().ok())
+        };
+        let date = parse_date(&record[date_col])?;
+        let station = station_col
+            .and_then(|c| record.get(c))
+            .unwrap_or_default()
+            .to_string();
+        if let (Some(latitude), Some(longitude)) = (value(latitude_col), value(longitude_col)) {
+            coordinates.insert(station.clone(), (latitude, longitude));
+        }
+        stations.weather.entry(station).or_default().insert(
+            date,
+            Weather {
+                precipitation: value(prcp_col),
+                tmax: value(tmax_col),
+                tmin: value(tmin_col),
+            },
+        );
+    }
+
+    for location in locations {
+        let mut nearest = coordinates.iter().collect::<Vec<_>>();
+        nearest.sort_by(|(_, a), (_, b)| {
+            let from = (location.latitude, location.longitude);
+            distance(from, **a).total_cmp(&distance(from, **b))
+        });
+        if !nearest.is_empty() {
+            stations.nearest.insert(
+                location.location_id,
+                nearest.into_iter().map(|(s, _)| s.clone()).collect(),
+            );
+        }
+    }
+    Ok(stations)
+}
+
+// The great-circle distance in km between two (latitude, longitude) points.
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat_a, lat_b) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * 6371.0 * h.sqrt().asin()
+}
+
+// Read a holiday calendar: a CSV with a header and columns date (YYYY-MM-DD) and name. A row whose
+// date can't be parsed is skipped with a warning, rather than losing the rest of the calendar.
+pub fn read_holidays(path: &str) -> Result<HashMap<NaiveDate, String>, AnnotationError> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let mut holidays = HashMap::new();
+    for result in rdr.records() {
+        let record = result?;
+        let date = match parse_date(record.get(0).unwrap_or_default()) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Skipping holiday in {path}: {e}");
+                continue;
+            }
+        };
+        let name = record.get(1).unwrap_or_default().trim().to_string();
+        holidays.insert(date, name);
+    }
+    Ok(holidays)
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, AnnotationError> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .map_err(|_| AnnotationError::Date(date.to_string()))
+}
+
+// Annotate each daily count with the day's weather at its location, holiday, and type of day.
+pub fn annotate(
+    daily_counts: &[AggregatedCount],
+    weather: &Stations,
+    holidays: &HashMap<NaiveDate, String>,
+) -> Vec<Annotation> {
+    daily_counts
+        .iter()
+        .map(|count| {
+            let holiday = holidays.get(&count.date).cloned();
+            let day_type = if holiday.is_some() {
+                DayType::Holiday
+            } else if matches!(count.date.weekday(), Weekday::Sat | Weekday::Sun) {
+                DayType::Weekend
+            } else {
+                DayType::Weekday
+            };
+            Annotation {
+                location_id: count.location_id,
+                date: count.date,
+                weather: weather.get(count.location_id, count.date),
+                holiday,
+                day_type,
+            }
+        })
+        .collect()
+}
+
+// Replace any annotations previously recorded for `dates` with `annotations`.
+pub fn insert_annotations(
+    conn: &Connection,
+    dates: &[NaiveDate],
+    annotations: &[Annotation],
+) -> Result<TableCounts, OracleError> {
+    let mut counts = TableCounts::default();
+    for date in dates {
+        counts.deleted += conn
+            .execute(
+                "delete from TBLANNOTATION where countdate = :1",
+                &[&to_timestamp(*date)],
+            )?
+            .row_count()?;
+    }
+    for a in annotations {
+        conn.execute(
+            "insert into TBLANNOTATION (locationid, countdate, precipitation, tmax, tmin, holiday, daytype) values (:1, :2, :3, :4, :5, :6, :7)",
+            &[
+                &a.location_id,
+                &to_timestamp(a.date),
+                &a.weather.precipitation,
+                &a.weather.tmax,
+                &a.weather.tmin,
+                &a.holiday,
+                &a.day_type.to_string(),
+            ],
+        )?;
+        counts.inserted += 1;
+    }
+    conn.commit()?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn write(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("eco-counter-import-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn location(location_id: i32, latitude: f64, longitude: f64) -> Location {
+        Location {
+            location_id,
+            name: location_id.to_string(),
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn each_location_gets_its_nearest_stations_weather() {
+        // A station in Philadelphia with no temperatures, and one in Trenton.
+        let path = write(
+            "weather.csv",
+            "STATION,NAME,LATITUDE,LONGITUDE,DATE,PRCP,TMAX,TMIN\n\
+             US1PAPH0001,PHILADELPHIA,39.95,-75.16,2024-06-01,0.0,,\n\
+             USW00014792,TRENTON,40.28,-74.82,2024-06-01,1.5,80,60\n",
+        );
+        let locations = [location(16, 39.93, -75.21), location(1, 40.22, -74.76)];
+        let stations = read_weather(&path, &locations).unwrap();
+        fs::remove_file(&path).ok();
+        let date = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+
+        // Bartram's Garden gets Philadelphia's precipitation, and Trenton's temperatures as the
+        // nearest station that has them.
+        let bartram = stations.get(16, date);
+        assert_eq!(
+            (bartram.precipitation, bartram.tmax, bartram.tmin),
+            (Some(0.0), Some(80.0), Some(60.0))
+        );
+        assert_eq!(stations.get(1, date).precipitation, Some(1.5));
+        // A location without coordinates gets the average.
+        assert_eq!(stations.get(99, date).precipitation, Some(0.75));
+    }
+
+    #[test]
+    fn holidays_with_bad_dates_are_skipped() {
+        let path = write(
+            "holidays.csv",
+            "date,name\n2024-07-04,Independence Day\n2024-13-01,Typo\n2024-12-25,Christmas\n",
+        );
+        let holidays = read_holidays(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(holidays.len(), 2);
+    }
+}
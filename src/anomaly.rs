@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::prelude::*;
+use oracle::sql_type::Timestamp;
+use oracle::{Connection, Error as OracleError};
+
+use crate::aggregation::AggregatedCount;
+use crate::db::to_timestamp;
+use crate::report::TableCounts;
+
+// Default robust z-score beyond which a day is flagged, used if ANOMALY_Z_THRESHOLD is not set.
+pub const DEFAULT_Z_THRESHOLD: f64 = 3.5;
+
+// Fewer baseline days than this and the statistics aren't worth much, so the day isn't checked.
+const MIN_BASELINE_DAYS: usize = 4;
+
+// Scales the median absolute deviation so it's comparable to a standard deviation.
+const MAD_SCALE: f64 = 0.6745;
+
+// A day whose total deviates too far from the same month and day-of-week in prior years.
+#[derive(Debug, Clone)]
+pub struct Anomaly {
+    pub location_id: i32,
+    pub date: NaiveDate,
+    pub total: i32,
+    pub baseline_median: f64,
+    pub baseline_mad: f64,
+    pub z: f64,
+}
+
+// Historical daily totals for each location, keyed by (location_id, year, month) of the days
+// being checked.
+pub type Baselines = HashMap<(i32, i32, u32), Vec<(NaiveDate, i32)>>;
+
+// Get the historical daily totals needed to check `daily_counts`: for each location and month in
+// them, every TBLHEADER total from that month in earlier years.
+pub fn get_baselines(
+    conn: &Connection,
+    daily_counts: &[AggregatedCount],
+) -> Result<Baselines, OracleError> {
+    let keys = daily_counts
+        .iter()
+        .map(|c| (c.location_id, c.date.year(), c.date.month()))
+        .collect::<HashSet<_>>();
+
+    let mut baselines = HashMap::new();
+    for (location_id, year, month) in keys {
+        let rows = conn.query_as::<(Timestamp, i32)>(
+            "select countdate, total from TBLHEADER where locationid = :1 and extract(month from countdate) = :2 and extract(year from countdate) < :3 and total is not null",
+            &[&location_id, &month, &year],
+        )?;
+        let mut history = vec![];
+        for row in rows {
+            let (countdate, total) = row?;
+            if let Some(date) =
+                NaiveDate::from_ymd_opt(countdate.year(), countdate.month(), countdate.day())
+            {
+                history.push((date, total));
+            }
+        }
+        baselines.insert((location_id, year, month), history);
+    }
+    Ok(baselines)
+}
+
+// Flag days whose total has a robust z-score (based on the median and median absolute deviation
+// of the same month and day-of-week in prior years) beyond `threshold`.
+pub fn detect(
+    daily_counts: &[AggregatedCount],
+    baselines: &Baselines,
+    threshold: f64,
+) -> Vec<Anomaly> {
+    let mut anomalies = vec![];
+    for count in daily_counts {
+        let Some(total) = count.total else {
+            continue;
+        };
+        let Some(history) =
+            baselines.get(&(count.location_id, count.date.year(), count.date.month()))
+        else {
+            continue;
+        };
+        let mut values = history
+            .iter()
+            .filter(|(date, _)| date.weekday() == count.date.weekday())
+            .map(|(_, total)| *total as f64)
+            .collect::<Vec<_>>();
+        if values.len() < MIN_BASELINE_DAYS {
+            continue;
+        }
+
+        let baseline_median = median(&mut values);
+        let mut deviations = values
+            .iter()
+            .map(|v| (v - baseline_median).abs())
+            .collect::<Vec<_>>();
+        let mad = median(&mut deviations);
+        // A baseline with no spread at all can't give a meaningful score.
+        if mad == 0.0 {
+            continue;
+        }
+
+        let z = MAD_SCALE * (total as f64 - baseline_median) / mad;
+        if z.abs() > threshold {
+            anomalies.push(Anomaly {
+                location_id: count.location_id,
+                date: count.date,
+                total,
+                baseline_median,
+                baseline_mad: mad,
+                z,
+            });
+        }
+    }
+    anomalies.sort_by_key(|a| (a.location_id, a.date));
+    anomalies
+}
+
+// The days checked and flagged for each location over an import, which may be checked a part at a
+// time.
+#[derive(Debug, Default)]
+pub struct Tally {
+    days: HashMap<i32, usize>,
+    flagged: HashMap<i32, usize>,
+}
+
+impl Tally {
+    pub fn add(&mut self, daily_counts: &[AggregatedCount], anomalies: &[Anomaly]) {
+        for count in daily_counts.iter().filter(|c| c.total.is_some()) {
+            *self.days.entry(count.location_id).or_insert(0) += 1;
+        }
+        for anomaly in anomalies {
+            *self.flagged.entry(anomaly.location_id).or_insert(0) += 1;
+        }
+    }
+
+    // Locations where at least half of the checked days (and at least three) were flagged, which
+    // points to a problem with the counter itself rather than an unusual day.
+    pub fn flagged_counters(&self) -> Vec<i32> {
+        let mut counters = self
+            .flagged
+            .iter()
+            .filter(|(location_id, n)| **n >= 3 && **n * 2 >= self.days[location_id])
+            .map(|(location_id, _)| *location_id)
+            .collect::<Vec<_>>();
+        counters.sort();
+        counters
+    }
+}
+
+// Replace any anomalies previously recorded for `dates` with `anomalies`.
+pub fn insert_anomalies(
+    conn: &Connection,
+    dates: &[NaiveDate],
+    anomalies: &[Anomaly],
+) -> Result<TableCounts, OracleError> {
+    let mut counts = TableCounts::default();
+    for date in dates {
+        counts.deleted += conn
+            .execute(
+                "delete from TBLANOMALY where countdate = :1",
+                &[&to_timestamp(*date)],
+            )?
+            .row_count()?;
+    }
+    for anomaly in anomalies {
+        conn.execute(
+            "insert into TBLANOMALY (locationid, countdate, total, baselinemedian, baselinemad, robustz) values (:1, :2, :3, :4, :5, :6)",
+            &[
+                &anomaly.location_id,
+                &to_timestamp(anomaly.date),
+                &anomaly.total,
+                &anomaly.baseline_median,
+                &anomaly.baseline_mad,
+                &anomaly.z,
+            ],
+        )?;
+        counts.inserted += 1;
+    }
+    conn.commit()?;
+    Ok(counts)
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
@@ -0,0 +1,547 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+
+use log::LevelFilter;
+use toml::{Table, Value};
+
+use crate::credentials::Credentials;
+use crate::db::DbConfig;
+use crate::error::Error;
+use crate::logging::LogConfig;
+use crate::notify::Mailer;
+use crate::pipeline::Settings;
+use crate::webhook::WebhookConfig;
+use crate::{anomaly, db, factors, geojson, logging, notify, pipeline, shutdown, webhook};
+
+// The config file read when --config isn't given, if it exists in the working directory.
+pub const DEFAULT_PATH: &str = "eco-counter-import.toml";
+
+const DEFAULT_CSV_FILE: &str = "export.csv";
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 15;
+
+// The default of a setting, as `config check` shows it. Where the default is a constant, it's
+// taken from the constant, so the two can't disagree.
+#[derive(Debug, Clone, Copy)]
+enum DefaultValue {
+    Text(&'static str),
+    Number(u64),
+    Decimal(f64),
+    Level(LevelFilter),
+}
+
+impl fmt::Display for DefaultValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefaultValue::Text(v) => write!(f, "{v}"),
+            DefaultValue::Number(v) => write!(f, "{v}"),
+            DefaultValue::Decimal(v) => write!(f, "{v}"),
+            DefaultValue::Level(v) => write!(f, "{}", v.as_str().to_lowercase()),
+        }
+    }
+}
+
+// Each setting of the config file, as (section, key, environment variable, default). The
+// environment variable, if set (including in the .env file), overrides the config file. The
+// defaults are only for `config check`; they're applied where each setting is read.
+const SETTINGS: &[(&str, &str, &str, Option<DefaultValue>)] = &[
+    ("storage", "path", "PATH_TO_CSV_AND_LOG", None),
+    (
+        "storage",
+        "csv_file",
+        "CSV_FILE",
+        Some(DefaultValue::Text(DEFAULT_CSV_FILE)),
+    ),
+    (
+        "storage",
+        "poll_interval",
+        "POLL_INTERVAL",
+        Some(DefaultValue::Number(DEFAULT_POLL_INTERVAL_SECS)),
+    ),
+    (
+        "storage",
+        "chunk_days",
+        "CHUNK_DAYS",
+        Some(DefaultValue::Number(pipeline::DEFAULT_CHUNK_DAYS as u64)),
+    ),
+    ("storage", "export_dir", "EXPORT_DIR", None),
+    ("database", "connect_string", "DB_CONNECT_STRING", None),
+    (
+        "database",
+        "pool_min",
+        "DB_POOL_MIN",
+        Some(DefaultValue::Number(db::DEFAULT_MIN_CONNECTIONS as u64)),
+    ),
+    (
+        "database",
+        "pool_max",
+        "DB_POOL_MAX",
+        Some(DefaultValue::Number(db::DEFAULT_MAX_CONNECTIONS as u64)),
+    ),
+    ("database", "stmt_cache_size", "DB_STMT_CACHE_SIZE", None),
+    (
+        "database",
+        "pool_wait_timeout",
+        "DB_POOL_WAIT_TIMEOUT",
+        None,
+    ),
+    ("database", "wallet_location", "DB_WALLET_LOCATION", None),
+    (
+        "database",
+        "retries",
+        "DB_RETRIES",
+        Some(DefaultValue::Number(db::DEFAULT_RETRIES as u64)),
+    ),
+    (
+        "database",
+        "auth",
+        "ECO_DB_AUTH",
+        Some(DefaultValue::Text("password")),
+    ),
+    ("database", "user", "ECO_DB_USER", None),
+    ("database", "user_file", "ECO_DB_USER_FILE", None),
+    ("database", "password", "ECO_DB_PASSWORD", None),
+    ("database", "password_file", "ECO_DB_PASSWORD_FILE", None),
+    (
+        "logging",
+        "target",
+        "LOG_TARGET",
+        Some(DefaultValue::Text("file")),
+    ),
+    (
+        "logging",
+        "format",
+        "LOG_FORMAT",
+        Some(DefaultValue::Text("text")),
+    ),
+    (
+        "logging",
+        "level",
+        "LOG_LEVEL",
+        Some(DefaultValue::Level(logging::DEFAULT_LEVEL)),
+    ),
+    (
+        "logging",
+        "terminal_level",
+        "LOG_TERMINAL_LEVEL",
+        Some(DefaultValue::Level(logging::DEFAULT_TERMINAL_LEVEL)),
+    ),
+    ("logging", "rotate", "LOG_ROTATE", None),
+    (
+        "logging",
+        "max_size_mb",
+        "LOG_MAX_SIZE_MB",
+        Some(DefaultValue::Number(logging::DEFAULT_MAX_SIZE_MB as u64)),
+    ),
+    (
+        "logging",
+        "keep",
+        "LOG_KEEP",
+        Some(DefaultValue::Number(logging::DEFAULT_KEEP as u64)),
+    ),
+    (
+        "analysis",
+        "anomaly_threshold",
+        "ANOMALY_Z_THRESHOLD",
+        Some(DefaultValue::Decimal(anomaly::DEFAULT_Z_THRESHOLD)),
+    ),
+    ("analysis", "weather_csv", "PATH_TO_WEATHER_CSV", None),
+    ("analysis", "holidays_csv", "PATH_TO_HOLIDAYS_CSV", None),
+    ("analysis", "factor_groups", "FACTOR_GROUPS", None),
+    ("webmap", "geojson", "PATH_TO_GEOJSON", None),
+    ("webmap", "locations_csv", "PATH_TO_LOCATIONS_CSV", None),
+    ("email", "server", "SMTP_SERVER", None),
+    (
+        "email",
+        "tls",
+        "SMTP_TLS",
+        Some(DefaultValue::Text(notify::DEFAULT_TLS)),
+    ),
+    ("email", "port", "SMTP_PORT", None),
+    ("email", "username", "SMTP_USERNAME", None),
+    ("email", "password", "SMTP_PASSWORD", None),
+    ("email", "from", "EMAIL_FROM", None),
+    ("email", "to", "EMAIL_TO", None),
+    (
+        "email",
+        "on_success",
+        "EMAIL_ON_SUCCESS",
+        Some(DefaultValue::Text("false")),
+    ),
+    ("webhooks", "urls", "WEBHOOK_URLS", None),
+    (
+        "webhooks",
+        "timeout",
+        "WEBHOOK_TIMEOUT",
+        Some(DefaultValue::Number(webhook::DEFAULT_TIMEOUT_SECS)),
+    ),
+    (
+        "webhooks",
+        "retries",
+        "WEBHOOK_RETRIES",
+        Some(DefaultValue::Number(webhook::DEFAULT_RETRIES as u64)),
+    ),
+    ("http", "addr", "HTTP_ADDR", None),
+    (
+        "shutdown",
+        "timeout",
+        "SHUTDOWN_TIMEOUT",
+        Some(DefaultValue::Number(shutdown::DEFAULT_TIMEOUT_SECS)),
+    ),
+];
+
+// Settings never printed by `config check`. Webhook URLs usually contain a token.
+const SECRETS: &[&str] = &["ECO_DB_PASSWORD", "SMTP_PASSWORD", "WEBHOOK_URLS"];
+
+// Read the config file at `path`, or at DEFAULT_PATH if there's one, setting the environment
+// variable of each setting in it that isn't already set. Database settings for a profile other
+// than the default go in [database.profiles.NAME], and set {NAME}_DB_... . Returns the variables
+// that were set from the file.
+//
+// Setting the environment isn't safe while another thread may be reading it, so this must be
+// called before any thread is started (as the .env file is loaded).
+pub fn load(path: Option<&str>) -> Result<Vec<String>, Error> {
+    let path = match path {
+        Some(v) => v,
+        None if Path::new(DEFAULT_PATH).exists() => DEFAULT_PATH,
+        None => return Ok(vec![]),
+    };
+    let table = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Could not read config file {path}: {e}")))?
+        .parse::<Table>()
+        .map_err(|e| Error::Config(format!("Invalid config file {path}: {e}")))?;
+
+    let unknown = |name: &str| Error::Config(format!("Unknown setting {name} in {path}."));
+    let mut vars = vec![];
+    for (section, keys) in &table {
+        for (key, value) in as_table(section, keys, path)? {
+            if section == "database" && key == "profiles" {
+                for (profile, keys) in as_table("database.profiles", value, path)? {
+                    let name = format!("database.profiles.{profile}");
+                    for (key, value) in as_table(&name, keys, path)? {
+                        let name = format!("{name}.{key}");
+                        let var = var_of(section, key)
+                            .filter(|var| var.starts_with("DB_"))
+                            .ok_or_else(|| unknown(&name))?;
+                        vars.push((
+                            format!("{}_{var}", profile.to_uppercase()),
+                            to_string(&name, value, path)?,
+                        ));
+                    }
+                }
+                continue;
+            }
+            let name = format!("{section}.{key}");
+            let var = var_of(section, key).ok_or_else(|| unknown(&name))?;
+            vars.push((var.to_string(), to_string(&name, value, path)?));
+        }
+    }
+
+    let mut set = vec![];
+    for (var, value) in vars {
+        if env::var_os(&var).is_none() {
+            env::set_var(&var, value);
+            set.push(var);
+        }
+    }
+    Ok(set)
+}
+
+fn var_of(section: &str, key: &str) -> Option<&'static str> {
+    SETTINGS
+        .iter()
+        .find(|(s, k, _, _)| *s == section && *k == key)
+        .map(|(_, _, var, _)| *var)
+}
+
+fn as_table<'a>(name: &str, value: &'a Value, path: &str) -> Result<&'a Table, Error> {
+    value.as_table().ok_or_else(|| {
+        Error::Config(format!(
+            "{name} in {path} must be a section, like [{name}]."
+        ))
+    })
+}
+
+// The value of a setting as it would be written in an environment variable: lists are
+// comma-separated, and tables (for factor groups) are `name:a,b;name:c`.
+fn to_string(name: &str, value: &Value, path: &str) -> Result<String, Error> {
+    let scalar = |value: &Value| match value {
+        Value::String(v) => Ok(v.clone()),
+        Value::Integer(v) => Ok(v.to_string()),
+        Value::Float(v) => Ok(v.to_string()),
+        Value::Boolean(v) => Ok(v.to_string()),
+        _ => Err(Error::Config(format!(
+            "Invalid value for {name} in {path}: {value}"
+        ))),
+    };
+    let list = |value: &Value| match value {
+        Value::Array(values) => Ok(values
+            .iter()
+            .map(scalar)
+            .collect::<Result<Vec<_>, _>>()?
+            .join(",")),
+        _ => scalar(value),
+    };
+    match value {
+        Value::Table(table) => Ok(table
+            .iter()
+            .map(|(key, value)| Ok(format!("{key}:{}", list(value)?)))
+            .collect::<Result<Vec<_>, Error>>()?
+            .join(";")),
+        _ => list(value),
+    }
+}
+
+// The whole configuration, validated. Loading it only reads and checks the settings: nothing is
+// started or changed until the program uses them (as `config check` doesn't).
+pub struct Config {
+    pub storage_path: String,
+    pub csv_path: String,
+    pub poll_interval: u64,
+    pub log: LogConfig,
+    pub shutdown_timeout: u64,
+    pub credentials: Credentials,
+    // Where the credentials came from, to log.
+    pub credentials_source: String,
+    pub mailer: Option<Mailer>,
+    pub webhooks: Option<WebhookConfig>,
+    pub http_addr: Option<String>,
+    pub db: DbConfig,
+    pub settings: Settings,
+}
+
+impl Config {
+    // Load and validate every setting, for the database profile `profile`, returning all of the
+    // problems found rather than only the first.
+    pub fn from_env(profile: &str) -> Result<Self, Vec<String>> {
+        let mut errors = vec![];
+
+        let storage_path = ok(
+            &mut errors,
+            match env::var("PATH_TO_CSV_AND_LOG") {
+                Ok(v) if Path::new(&v).is_dir() => Ok(v),
+                Ok(v) => Err(format!("PATH_TO_CSV_AND_LOG ({v}) is not a directory.")),
+                Err(_) => Err("PATH_TO_CSV_AND_LOG is required.".to_string()),
+            },
+        );
+        let csv_file = ok(
+            &mut errors,
+            match env::var("CSV_FILE") {
+                Ok(v) if v.is_empty() || v.contains('/') => Err(format!(
+                    "CSV_FILE must be a file name, without a directory, got {v}."
+                )),
+                Ok(v) => Ok(v),
+                Err(_) => Ok(DEFAULT_CSV_FILE.to_string()),
+            },
+        );
+        let poll_interval = ok(
+            &mut errors,
+            match env::var("POLL_INTERVAL") {
+                Ok(v) => match v.parse() {
+                    Ok(v) if v > 0 => Ok(v),
+                    _ => Err(format!(
+                        "POLL_INTERVAL must be a positive number of seconds, got {v}."
+                    )),
+                },
+                Err(_) => Ok(DEFAULT_POLL_INTERVAL_SECS),
+            },
+        );
+        let chunk_days = ok(
+            &mut errors,
+            match env::var("CHUNK_DAYS") {
+                Ok(v) => match v.parse() {
+                    Ok(v) if v > 0 => Ok(v),
+                    _ => Err(format!(
+                        "CHUNK_DAYS must be a positive number of days, got {v}."
+                    )),
+                },
+                Err(_) => Ok(pipeline::DEFAULT_CHUNK_DAYS),
+            },
+        );
+        let log = ok(&mut errors, LogConfig::from_env());
+        let shutdown_timeout = ok(&mut errors, shutdown::timeout_from_env());
+        let credentials = ok(&mut errors, Credentials::load().map_err(|e| e.to_string()));
+        let db = ok(
+            &mut errors,
+            DbConfig::from_env(profile).map_err(|e| e.to_string()),
+        );
+        // Threshold for flagging days that deviate from their historical baseline.
+        let anomaly_threshold = ok(
+            &mut errors,
+            match env::var("ANOMALY_Z_THRESHOLD") {
+                Ok(v) => match v.parse::<f64>() {
+                    Ok(v) if v > 0.0 => Ok(v),
+                    _ => Err(format!(
+                        "ANOMALY_Z_THRESHOLD must be a positive number, got {v}."
+                    )),
+                },
+                Err(_) => Ok(anomaly::DEFAULT_Z_THRESHOLD),
+            },
+        );
+
+        // Groups of locations to calculate expansion factors for.
+        let factor_groups = ok(
+            &mut errors,
+            match env::var("FACTOR_GROUPS") {
+                Ok(v) => {
+                    factors::parse_groups(&v).map_err(|e| format!("Invalid FACTOR_GROUPS: {e}"))
+                }
+                Err(_) => Ok(vec![]),
+            },
+        );
+
+        // The locations of the counters, for the webmap export and to find their nearest weather
+        // stations.
+        let geojson_path = env::var("PATH_TO_GEOJSON").ok();
+        let locations = ok(
+            &mut errors,
+            match (&geojson_path, env::var("PATH_TO_LOCATIONS_CSV")) {
+                (_, Ok(v)) => geojson::read_locations(&v)
+                    .map_err(|e| format!("Unable to load locations from {v}: {e}")),
+                (Some(_), Err(_)) => {
+                    Err("PATH_TO_LOCATIONS_CSV is required to export GeoJSON.".to_string())
+                }
+                (None, Err(_)) => Ok(vec![]),
+            },
+        );
+
+        let mailer = ok(
+            &mut errors,
+            Mailer::from_env().map_err(|e| format!("Invalid email settings: {e}")),
+        );
+        let webhooks = ok(
+            &mut errors,
+            WebhookConfig::from_env().map_err(|e| format!("Invalid webhook settings: {e}")),
+        );
+
+        let (
+            Some(storage_path),
+            Some(csv_file),
+            Some(poll_interval),
+            Some(chunk_days),
+            Some(log),
+            Some(shutdown_timeout),
+            Some((credentials, credentials_source)),
+            Some(db),
+            Some(anomaly_threshold),
+            Some(factor_groups),
+            Some(locations),
+            Some(mailer),
+            Some(webhooks),
+        ) = (
+            storage_path,
+            csv_file,
+            poll_interval,
+            chunk_days,
+            log,
+            shutdown_timeout,
+            credentials,
+            db,
+            anomaly_threshold,
+            factor_groups,
+            locations,
+            mailer,
+            webhooks,
+        )
+        else {
+            return Err(errors);
+        };
+
+        Ok(Self {
+            csv_path: format!("{storage_path}/{csv_file}"),
+            storage_path,
+            poll_interval,
+            log,
+            shutdown_timeout,
+            credentials,
+            credentials_source,
+            mailer,
+            webhooks,
+            http_addr: env::var("HTTP_ADDR").ok(),
+            db,
+            settings: Settings {
+                anomaly_threshold,
+                weather_path: env::var("PATH_TO_WEATHER_CSV").ok(),
+                holidays_path: env::var("PATH_TO_HOLIDAYS_CSV").ok(),
+                factor_groups,
+                geojson_path,
+                locations,
+                export_dir: env::var("EXPORT_DIR").ok(),
+                chunk_days,
+            },
+        })
+    }
+}
+
+// The value of a setting, or None after recording why it's invalid.
+fn ok<T>(errors: &mut Vec<String>, result: Result<T, String>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+// Print the effective configuration for `profile`, as a config file with where each setting came
+// from and secrets redacted, followed by whether it's valid.
+pub fn check(profile: &str, from_file: &[String]) -> ExitCode {
+    let print = |key: &str, var: &str, default: Option<DefaultValue>| {
+        let (value, source) = match (env::var(var), default) {
+            (Ok(v), _) if from_file.iter().any(|f| f == var) => (v, "from config file"),
+            (Ok(v), _) => (v, "from environment"),
+            (Err(_), Some(v)) => (v.to_string(), "default"),
+            (Err(_), None) => {
+                println!("# {key} is not set  # {var}");
+                return;
+            }
+        };
+        let value = if SECRETS.contains(&var) {
+            "[redacted]".to_string()
+        } else if value.parse::<f64>().is_ok() || value.parse::<bool>().is_ok() {
+            value
+        } else {
+            Value::String(value).to_string()
+        };
+        println!("{key} = {value}  # {var}, {source}");
+    };
+
+    match DbConfig::from_env(profile) {
+        Ok(db) => println!(
+            "# Database profile: {profile} (connect string {})",
+            db.connect_string
+        ),
+        Err(_) => println!("# Database profile: {profile}"),
+    }
+    let mut section = "";
+    for (s, key, var, default) in SETTINGS {
+        if *s != section {
+            println!("\n[{s}]");
+            section = s;
+        }
+        print(key, var, *default);
+    }
+    let profile_settings = SETTINGS
+        .iter()
+        .filter(|(s, _, var, _)| *s == "database" && var.starts_with("DB_"))
+        .map(|(_, key, var, _)| (key, format!("{}_{var}", profile.to_uppercase())))
+        .filter(|(_, var)| env::var_os(var).is_some())
+        .collect::<Vec<_>>();
+    if !profile_settings.is_empty() {
+        println!("\n[database.profiles.{profile}]");
+        for (key, var) in profile_settings {
+            print(key, &var, None);
+        }
+    }
+
+    match Config::from_env(profile) {
+        Ok(_) => {
+            println!("\nThe configuration is valid.");
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            eprintln!("\nThe configuration is invalid:");
+            for e in errors {
+                eprintln!("  - {e}");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+
+use crate::error::Error;
+
+// A value that's never shown when printed or logged.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+// How to authenticate with the database.
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Password { username: String, password: Secret },
+    // External authentication, with the credentials in the Oracle wallet rather than given here.
+    External,
+}
+
+impl Credentials {
+    // Load the credentials, with ECO_DB_AUTH=external to use external authentication, or else the
+    // username and password each from the first of:
+    //   - ECO_DB_USER_FILE / ECO_DB_PASSWORD_FILE: a file containing it (e.g. a Docker secret or
+    //     systemd credential)
+    //   - ECO_DB_USER / ECO_DB_PASSWORD
+    //   - USERNAME / PASSWORD in the .env file. These are only read from the file itself, as
+    //     USERNAME is often already set in the environment by the OS.
+    // Also returns a description of where they came from (never the values themselves), to log.
+    pub fn load() -> Result<(Self, String), Error> {
+        match env::var("ECO_DB_AUTH").as_deref() {
+            Ok("external") => return Ok((Credentials::External, "external authentication".into())),
+            Ok("password") | Err(_) => (),
+            Ok(v) => {
+                return Err(Error::Config(format!(
+                    "ECO_DB_AUTH must be password or external, got {v}."
+                )))
+            }
+        }
+
+        let dotenv = match dotenvy::dotenv_iter() {
+            Ok(iter) => iter.collect::<Result<HashMap<_, _>, _>>().map_err(|e| {
+                Error::Config(format!("Could not read .env file: {}", dotenv_error(&e)))
+            })?,
+            Err(e) if e.not_found() => HashMap::new(),
+            Err(e) => {
+                return Err(Error::Config(format!(
+                    "Could not read .env file: {}",
+                    dotenv_error(&e)
+                )))
+            }
+        };
+        let (username, user_source) = lookup("ECO_DB_USER", "USERNAME", &dotenv)?
+            .ok_or_else(|| missing("username", "ECO_DB_USER"))?;
+        let (password, password_source) = lookup("ECO_DB_PASSWORD", "PASSWORD", &dotenv)?
+            .ok_or_else(|| missing("password", "ECO_DB_PASSWORD"))?;
+
+        Ok((
+            Credentials::Password {
+                username,
+                password: Secret(password),
+            },
+            format!("username from {user_source}, password from {password_source}"),
+        ))
+    }
+}
+
+// Find a credential in {var}_FILE, then {var}, then `dotenv_var` in the .env file, returning it
+// and where it was found.
+fn lookup(
+    var: &str,
+    dotenv_var: &str,
+    dotenv: &HashMap<String, String>,
+) -> Result<Option<(String, String)>, Error> {
+    let file_var = format!("{var}_FILE");
+    if let Ok(path) = env::var(&file_var) {
+        let value = fs::read_to_string(&path)
+            .map_err(|e| Error::Config(format!("Could not read {file_var} ({path}): {e}")))?;
+        // Files usually end with a newline, which isn't part of the value.
+        return Ok(Some((
+            value.trim_end_matches(['\r', '\n']).to_string(),
+            file_var,
+        )));
+    }
+    if let Ok(value) = env::var(var) {
+        return Ok(Some((value, var.to_string())));
+    }
+    Ok(dotenv
+        .get(dotenv_var)
+        .map(|value| (value.clone(), format!("{dotenv_var} in .env"))))
+}
+
+// Why the .env file couldn't be read. dotenvy's message for a line it can't parse includes the
+// line, which may hold a password, so only its number is given.
+pub fn dotenv_error(e: &dotenvy::Error) -> String {
+    let content = env::current_dir().ok().and_then(|dir| {
+        dir.ancestors()
+            .map(|dir| dir.join(".env"))
+            .find(|path| path.is_file())
+            .and_then(|path| fs::read_to_string(path).ok())
+    });
+    describe_dotenv_error(e, content.as_deref())
+}
+
+fn describe_dotenv_error(e: &dotenvy::Error, content: Option<&str>) -> String {
+    match e {
+        dotenvy::Error::LineParse(line, _) => {
+            let start = content.and_then(|c| Some(&c[..c.find(line.as_str())?]));
+            match start.map(|s| s.matches('\n').count() + 1) {
+                Some(number) => format!("line {number} can't be parsed"),
+                None => "a line can't be parsed".to_string(),
+            }
+        }
+        // Which would include the value.
+        dotenvy::Error::EnvVar(env::VarError::NotUnicode(_)) => {
+            "a value isn't valid Unicode".to_string()
+        }
+        e => e.to_string(),
+    }
+}
+
+fn missing(what: &str, var: &str) -> Error {
+    Error::Config(format!(
+        "No database {what}; set {var}_FILE or {var} (or ECO_DB_AUTH=external to use the wallet)."
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unparseable_line_is_given_by_number_only() {
+        let content = "USERNAME=importer\nPASSWORD='hunter 2\nOTHER=1\n";
+        let e = dotenvy::from_read_iter(content.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_err();
+        assert!(e.to_string().contains("hunter"));
+        assert_eq!(
+            describe_dotenv_error(&e, Some(content)),
+            "line 2 can't be parsed"
+        );
+        assert_eq!(describe_dotenv_error(&e, None), "a line can't be parsed");
+    }
+}
@@ -0,0 +1,343 @@
+use std::env;
+use std::str::FromStr;
+#[cfg(feature = "service")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use log::warn;
+#[cfg(feature = "service")]
+use log::{error, info};
+#[cfg(feature = "service")]
+use oracle::pool::CloseMode;
+use oracle::pool::{GetMode, Pool, PoolBuilder, PoolType};
+use oracle::sql_type::Timestamp;
+use oracle::{Connection, Connector, Error as OracleError};
+
+use crate::credentials::Credentials;
+use crate::error::Error;
+#[cfg(feature = "service")]
+use crate::metrics::Metrics;
+#[cfg(feature = "service")]
+use crate::{shutdown, systemd};
+
+pub const DEFAULT_PROFILE: &str = "prod";
+
+// The production database (from tnsnames.ora), used by the prod profile unless overridden.
+const PROD_CONNECT_STRING: &str = "dvrpcprod_tp_tls";
+
+// Worker threads each hold a connection, so the maximum size of the pool is also the number of
+// worker threads. It's limited in order to limit the number of concurrent connections to the
+// database, otherwise this could easily triple to improve performance.
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+pub const DEFAULT_MIN_CONNECTIONS: u32 = 1;
+
+// Waits between attempts to reconnect to the database, doubling from the initial to the maximum.
+#[cfg(feature = "service")]
+const INITIAL_BACKOFF_SECS: u64 = 1;
+#[cfg(feature = "service")]
+const MAX_BACKOFF_SECS: u64 = 300;
+
+// Retries of a batch that failed with a transient error, and the waits between them, doubling
+// from the initial to the maximum (with jitter, so that worker threads don't retry in lockstep).
+pub const DEFAULT_RETRIES: u32 = 3;
+const INITIAL_RETRY_DELAY_MS: u64 = 500;
+const MAX_RETRY_DELAY_MS: u64 = 30_000;
+
+// ORA codes of errors that may not happen again if retried: deadlock (60), resource busy (54),
+// lock timeout (30006), the database starting up or shutting down (1033, 1034, 1089), and
+// connection/network failures.
+const TRANSIENT_CODES: &[i32] = &[
+    54, 60, 1033, 1034, 1089, 2396, 3113, 3114, 3135, 12170, 12514, 12516, 12519, 12520, 12528,
+    12537, 12541, 12543, 12545, 12547, 12571, 25408, 30006,
+];
+
+// Of those, the ones after which the connection can't be used again.
+const CONNECTION_LOST_CODES: &[i32] = &[
+    1033, 1034, 1089, 2396, 3113, 3114, 3135, 12537, 12547, 12571,
+];
+
+// ODPI-C errors (which have no ORA code) about the connection: not connected (1010), call
+// timeout (1067) and closed by an ORA error (1080).
+const CONNECTION_LOST_DPI: &[&str] = &["DPI-1010", "DPI-1067", "DPI-1080"];
+
+// How to connect to the database, for one profile (e.g. prod, staging, dev).
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    pub profile: String,
+    pub connect_string: String,
+    pub min_connections: u32,
+    pub max_connections: u32,
+    pub stmt_cache_size: Option<u32>,
+    // How long to wait for a free connection from the pool before giving up (not how long to
+    // wait for the database to answer a new connection).
+    pub pool_wait_timeout: Option<Duration>,
+    // Directory with the wallet, tnsnames.ora and sqlnet.ora; sets TNS_ADMIN.
+    pub wallet_location: Option<String>,
+    // Times to retry a batch that failed with a transient error.
+    pub retries: u32,
+}
+
+impl DbConfig {
+    // Load the settings for `profile` from the environment. Each setting is taken from
+    // {PROFILE}_DB_..., then DB_..., then the default.
+    pub fn from_env(profile: &str) -> Result<Self, Error> {
+        let var = |name: &str| {
+            env::var(format!("{}_{name}", profile.to_uppercase()))
+                .or_else(|_| env::var(name))
+                .ok()
+        };
+        fn parse<T: FromStr>(name: &str, v: Option<String>) -> Result<Option<T>, Error> {
+            v.map(|v| {
+                v.parse().map_err(|_| {
+                    Error::Config(format!("{name} must be a positive number, got {v}."))
+                })
+            })
+            .transpose()
+        }
+
+        let connect_string = match var("DB_CONNECT_STRING") {
+            Some(v) => v,
+            None if profile == DEFAULT_PROFILE => PROD_CONNECT_STRING.to_string(),
+            None => {
+                return Err(Error::Config(format!(
+                    "No connect string for profile {profile}; set {}_DB_CONNECT_STRING.",
+                    profile.to_uppercase()
+                )))
+            }
+        };
+        let min_connections =
+            parse("DB_POOL_MIN", var("DB_POOL_MIN"))?.unwrap_or(DEFAULT_MIN_CONNECTIONS);
+        let max_connections =
+            parse("DB_POOL_MAX", var("DB_POOL_MAX"))?.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        if max_connections == 0 || min_connections > max_connections {
+            return Err(Error::Config(format!(
+                "DB_POOL_MAX ({max_connections}) must be at least 1 and at least DB_POOL_MIN ({min_connections})."
+            )));
+        }
+
+        Ok(Self {
+            profile: profile.to_string(),
+            connect_string,
+            min_connections,
+            max_connections,
+            stmt_cache_size: parse("DB_STMT_CACHE_SIZE", var("DB_STMT_CACHE_SIZE"))?,
+            pool_wait_timeout: parse("DB_POOL_WAIT_TIMEOUT", var("DB_POOL_WAIT_TIMEOUT"))?
+                .map(Duration::from_secs),
+            wallet_location: var("DB_WALLET_LOCATION"),
+            retries: parse("DB_RETRIES", var("DB_RETRIES"))?.unwrap_or(DEFAULT_RETRIES),
+        })
+    }
+
+    // Point the Oracle client at the wallet location, if set. This has to be done before any
+    // connection is made, and, as it sets an environment variable, before any other thread is
+    // started.
+    pub fn apply_wallet_location(&self) {
+        if let Some(dir) = &self.wallet_location {
+            env::set_var("TNS_ADMIN", dir);
+        }
+    }
+
+    pub fn pool(&self, credentials: &Credentials) -> Result<Pool, OracleError> {
+        let mut builder = match credentials {
+            Credentials::Password { username, password } => {
+                PoolBuilder::new(username.as_str(), password.expose(), &self.connect_string)
+            }
+            // External authentication needs a heterogeneous pool.
+            Credentials::External => {
+                let mut builder = PoolBuilder::new("", "", &self.connect_string);
+                builder
+                    .external_auth(true)
+                    .pool_type(PoolType::Heterogeneous);
+                builder
+            }
+        };
+        builder
+            .min_connections(self.min_connections)
+            .max_connections(self.max_connections);
+        if let Some(size) = self.stmt_cache_size {
+            builder.stmt_cache_size(size);
+        }
+        if let Some(timeout) = self.pool_wait_timeout {
+            builder.get_mode(GetMode::TimedWait(timeout));
+        }
+        builder.build()
+    }
+
+    // A single connection, outside of a pool.
+    pub fn connect(&self, credentials: &Credentials) -> Result<Connection, OracleError> {
+        let mut connector = match credentials {
+            Credentials::Password { username, password } => {
+                Connector::new(username.as_str(), password.expose(), &self.connect_string)
+            }
+            Credentials::External => {
+                let mut connector = Connector::new("", "", &self.connect_string);
+                connector.external_auth(true);
+                connector
+            }
+        };
+        if let Some(size) = self.stmt_cache_size {
+            connector.stmt_cache_size(size);
+        }
+        connector.connect()
+    }
+}
+
+// A connection pool kept for the life of the program, rebuilt when the database can't be reached
+// (e.g. after ORA-03113 or ORA-12170).
+#[cfg(feature = "service")]
+pub struct Database {
+    config: DbConfig,
+    credentials: Credentials,
+    pool: Option<Pool>,
+}
+
+#[cfg(feature = "service")]
+impl Database {
+    pub fn new(config: DbConfig, credentials: Credentials) -> Self {
+        Self {
+            config,
+            credentials,
+            pool: None,
+        }
+    }
+
+    // Whether the database can currently be reached through the pool.
+    pub fn is_reachable(&self) -> bool {
+        self.pool
+            .as_ref()
+            .is_some_and(|pool| pool.get().and_then(|conn| conn.ping()).is_ok())
+    }
+
+    // Return the pool once the database is reachable: the existing one if it's healthy, or else a
+    // new one, retrying with exponential backoff until it can connect. Returns None if a shutdown
+    // is requested while waiting.
+    pub fn ensure(&mut self, shutdown: &AtomicBool, metrics: &Metrics) -> Option<&Pool> {
+        let mut backoff = INITIAL_BACKOFF_SECS;
+        loop {
+            if self.is_reachable() {
+                return self.pool.as_ref();
+            }
+            if self.pool.take().is_some() {
+                warn!("Lost connection to database; rebuilding the connection pool.");
+            }
+            match self
+                .config
+                .pool(&self.credentials)
+                .and_then(|pool| pool.get().and_then(|conn| conn.ping()).map(|_| pool))
+            {
+                Ok(pool) => {
+                    info!("Connected to database.");
+                    self.pool = Some(pool);
+                    return self.pool.as_ref();
+                }
+                Err(e) => {
+                    metrics.pool_error();
+                    error!("Unable to connect to database, retrying in {backoff}s: {e}");
+                    systemd::status(&format!(
+                        "Unable to connect to database, retrying in {backoff}s."
+                    ));
+                }
+            }
+            systemd::watchdog();
+            shutdown::wait(shutdown, backoff);
+            if shutdown.load(Ordering::Relaxed) {
+                return None;
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF_SECS);
+        }
+    }
+
+    pub fn close(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            if let Err(e) = pool.close(&CloseMode::Default) {
+                error!("Error closing connection pool: {e}");
+            }
+        }
+    }
+}
+
+// Whether an error is worth retrying, and if so whether it needs a new connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Permanent,
+    Transient,
+    ConnectionLost,
+}
+
+pub fn classify(e: &OracleError) -> ErrorKind {
+    match e {
+        OracleError::OciError(e) if CONNECTION_LOST_CODES.contains(&e.code()) => {
+            ErrorKind::ConnectionLost
+        }
+        OracleError::OciError(e) if TRANSIENT_CODES.contains(&e.code()) => ErrorKind::Transient,
+        OracleError::DpiError(e)
+            if CONNECTION_LOST_DPI
+                .iter()
+                .any(|code| e.message().starts_with(code)) =>
+        {
+            ErrorKind::ConnectionLost
+        }
+        _ => ErrorKind::Permanent,
+    }
+}
+
+// Run a batch of work on `conn`, retrying it up to `retries` times if it fails with a transient
+// error. A batch is one transaction, e.g. replacing a date's records: its deletes from
+// TBLCOUNTDATA and TBLHEADER, the inserts of all its counts, and the commit, so a retry runs the
+// whole date again. Before each retry, the batch is rolled back, or `conn` replaced with a new
+// connection from the pool if it was lost. A batch that fails for good is rolled back too.
+// `what` describes the batch, for the logs and the error (e.g. "replace records for
+// 2024-01-03").
+pub fn with_retries<T>(
+    pool: &Pool,
+    conn: &mut Connection,
+    retries: u32,
+    what: &str,
+    mut batch: impl FnMut(&Connection) -> Result<T, OracleError>,
+) -> Result<T, Error> {
+    let mut delay = INITIAL_RETRY_DELAY_MS;
+    let mut attempt = 0;
+    loop {
+        let e = match batch(conn) {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        attempt += 1;
+        let kind = classify(&e);
+        if kind == ErrorKind::Permanent || attempt > retries {
+            // Don't leave a half-done batch on a connection that goes back to the pool.
+            if kind != ErrorKind::ConnectionLost {
+                let _ = conn.rollback();
+            }
+            return Err(Error::Database {
+                context: what.to_string(),
+                source: e,
+                attempts: attempt,
+            });
+        }
+
+        // Wait between half and all of the delay.
+        let wait = delay / 2 + fastrand::u64(0..=delay / 2);
+        warn!("Could not {what}, retrying in {wait}ms: {e}");
+        thread::sleep(Duration::from_millis(wait));
+        delay = (delay * 2).min(MAX_RETRY_DELAY_MS);
+
+        match kind {
+            ErrorKind::ConnectionLost => match pool.get() {
+                Ok(new) => *conn = new,
+                // The next attempt will fail on the old connection, and try again.
+                Err(e) => warn!("Unable to get a new connection from the pool: {e}"),
+            },
+            _ => {
+                let _ = conn.rollback();
+            }
+        }
+    }
+}
+
+// A date as Oracle's DATE type, at midnight.
+pub fn to_timestamp(date: NaiveDate) -> Timestamp {
+    Timestamp::new(date.year(), date.month(), date.day(), 0, 0, 0, 0)
+}
@@ -0,0 +1,139 @@
+use std::any::Any;
+use std::error;
+use std::fmt;
+
+#[cfg(feature = "pipeline")]
+use oracle::Error as OracleError;
+
+#[cfg(feature = "pipeline")]
+use crate::db::{self, ErrorKind};
+
+// ORA codes that mean the program or the database is set up wrong, rather than anything about the
+// file being imported: invalid credentials (1017), account locked (28000), password expired
+// (28001), insufficient privileges (1031), table or view does not exist (942), and unresolvable
+// connect identifier (12154).
+#[cfg(feature = "pipeline")]
+const SETUP_CODES: &[i32] = &[942, 1017, 1031, 12154, 28000, 28001];
+
+// Why an import failed.
+#[derive(Debug)]
+pub enum Error {
+    // The file couldn't be read as an export (e.g. a missing header or an unparseable date).
+    Parse(String),
+    // The file was read, but its contents aren't what's expected (e.g. the wrong number of fields).
+    Validation(String),
+    // The program is misconfigured, so no file can be imported until it's fixed.
+    Config(String),
+    // A database operation failed, after `attempts` tries; `context` says what it was (e.g.
+    // "insert individual counts for location 5 on 2024-01-03").
+    #[cfg(feature = "pipeline")]
+    Database {
+        context: String,
+        source: OracleError,
+        attempts: u32,
+    },
+    // A shutdown was requested before the import finished. Every chunk imported before it is
+    // complete, and importing the file again replaces them.
+    Shutdown,
+    // A worker thread panicked, with its message.
+    Panic(String),
+}
+
+// What to do with the CSV file after an import fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // Leave it in place, to be imported again.
+    Retry,
+    // Move it aside, as importing it again would fail the same way. Only if the database hasn't
+    // been changed, or the file would be left partly imported with nothing to finish it.
+    Quarantine,
+    // Leave it in place and stop the program, as nothing can be imported until it's fixed.
+    Abort,
+}
+
+impl Error {
+    #[cfg(feature = "pipeline")]
+    pub fn database(context: impl Into<String>, source: OracleError) -> Self {
+        Error::Database {
+            context: context.into(),
+            source,
+            attempts: 1,
+        }
+    }
+
+    // The message of a worker thread's panic.
+    pub fn panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown cause".to_string()
+        };
+        Error::Panic(message)
+    }
+
+    // A short name for the kind of error, for logs and reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Parse(_) => "parse",
+            Error::Validation(_) => "validation",
+            Error::Config(_) => "config",
+            #[cfg(feature = "pipeline")]
+            Error::Database { .. } => "database",
+            Error::Shutdown => "shutdown",
+            Error::Panic(_) => "panic",
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        match self {
+            Error::Parse(_) | Error::Validation(_) => Action::Quarantine,
+            // A panic is a bug in the program rather than anything about the file.
+            Error::Config(_) | Error::Panic(_) => Action::Abort,
+            Error::Shutdown => Action::Retry,
+            #[cfg(feature = "pipeline")]
+            Error::Database { source, .. } => match db::classify(source) {
+                ErrorKind::Transient | ErrorKind::ConnectionLost => Action::Retry,
+                ErrorKind::Permanent => match source {
+                    OracleError::OciError(e) if SETUP_CODES.contains(&e.code()) => Action::Abort,
+                    _ => Action::Quarantine,
+                },
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(message) | Error::Validation(message) | Error::Config(message) => {
+                write!(f, "{message}")
+            }
+            #[cfg(feature = "pipeline")]
+            Error::Database {
+                context,
+                source,
+                attempts: 1,
+            } => write!(f, "Could not {context}: {source}"),
+            #[cfg(feature = "pipeline")]
+            Error::Database {
+                context,
+                source,
+                attempts,
+            } => write!(f, "Could not {context} after {attempts} attempts: {source}"),
+            Error::Shutdown => write!(f, "Import stopped by shutdown."),
+            Error::Panic(message) => write!(f, "A worker thread panicked: {message}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "pipeline")]
+            Error::Database { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
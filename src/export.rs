@@ -0,0 +1,532 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use parquet::basic::Compression;
+use parquet::data_type::{DataType, DoubleType, Int32Type, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::record::Field;
+use parquet::schema::parser::parse_message_type;
+
+use crate::aggregation::AggregatedCount;
+use crate::parser::IndividualCount;
+use crate::peak::PeakHour;
+
+const INDIVIDUAL_SCHEMA: &str = "
+message individual_count {
+    REQUIRED INT32 location_id;
+    REQUIRED INT64 datetime (TIMESTAMP(MILLIS,false));
+    OPTIONAL INT32 total;
+    OPTIONAL INT32 ped_in;
+    OPTIONAL INT32 ped_out;
+    OPTIONAL INT32 bike_in;
+    OPTIONAL INT32 bike_out;
+}
+";
+
+const DAILY_SCHEMA: &str = "
+message daily_count {
+    REQUIRED INT32 location_id;
+    REQUIRED INT32 date (DATE);
+    OPTIONAL INT32 total_ped;
+    OPTIONAL INT32 total_bike;
+    OPTIONAL INT32 total;
+    OPTIONAL INT64 am_peak_start (TIMESTAMP(MILLIS,false));
+    OPTIONAL INT32 am_peak_total;
+    OPTIONAL INT32 am_peak_ped;
+    OPTIONAL INT32 am_peak_bike;
+    OPTIONAL DOUBLE am_phf;
+    OPTIONAL INT64 pm_peak_start (TIMESTAMP(MILLIS,false));
+    OPTIONAL INT32 pm_peak_total;
+    OPTIONAL INT32 pm_peak_ped;
+    OPTIONAL INT32 pm_peak_bike;
+    OPTIONAL DOUBLE pm_phf;
+}
+";
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Parquet(ParquetError),
+    Csv(csv::Error),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(e) => write!(f, "{e}"),
+            ExportError::Parquet(e) => write!(f, "{e}"),
+            ExportError::Csv(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(e: io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
+
+impl From<ParquetError> for ExportError {
+    fn from(e: ParquetError) -> Self {
+        ExportError::Parquet(e)
+    }
+}
+
+impl From<csv::Error> for ExportError {
+    fn from(e: csv::Error) -> Self {
+        ExportError::Csv(e)
+    }
+}
+
+// Values of one column, in the order of the rows.
+enum Column {
+    Int32(Vec<Option<i32>>),
+    Int64(Vec<Option<i64>>),
+    Double(Vec<Option<f64>>),
+}
+
+// Export individual and daily counts to `dir`, as:
+//   - Parquet, partitioned by year, month, and location:
+//     parquet/{individual,daily}/year=YYYY/month=MM/location=ID/counts.parquet
+//   - tidy long-format CSV, partitioned by year and month:
+//     csv/year=YYYY/month=MM/{individual,daily}.csv
+// Like the import into the database, the counts replace any previously exported on the same dates,
+// for every location, and the rest of each partition is kept. Returns the number of files written.
+pub fn export(
+    dir: &str,
+    counts: &[IndividualCount],
+    daily_counts: &[AggregatedCount],
+) -> Result<usize, ExportError> {
+    let dir = Path::new(dir);
+    let mut num_files = 0;
+
+    // The dates being replaced, by month.
+    let mut months: BTreeMap<(i32, u32), BTreeSet<NaiveDate>> = BTreeMap::new();
+    let mut individual: BTreeMap<(i32, u32), BTreeMap<i32, Vec<IndividualCount>>> = BTreeMap::new();
+    for count in counts {
+        let date = count.datetime.date();
+        months
+            .entry((date.year(), date.month()))
+            .or_default()
+            .insert(date);
+        individual
+            .entry((date.year(), date.month()))
+            .or_default()
+            .entry(count.location_id)
+            .or_default()
+            .push(count.clone());
+    }
+    let mut daily: BTreeMap<(i32, u32), BTreeMap<i32, Vec<AggregatedCount>>> = BTreeMap::new();
+    for count in daily_counts {
+        months
+            .entry((count.date.year(), count.date.month()))
+            .or_default()
+            .insert(count.date);
+        daily
+            .entry((count.date.year(), count.date.month()))
+            .or_default()
+            .entry(count.location_id)
+            .or_default()
+            .push(count.clone());
+    }
+
+    for (&(year, month), dates) in &months {
+        let mut locations = individual.remove(&(year, month)).unwrap_or_default();
+        for counts in locations.values_mut() {
+            counts.sort_by_key(|c| c.datetime);
+        }
+        let parquet_dir = partition(dir, "parquet/individual", year, month);
+        for location_id in partition_locations(&parquet_dir, locations.keys())? {
+            let path = parquet_dir.join(format!("location={location_id}/counts.parquet"));
+            let mut counts = read_individual(&path)?;
+            counts.retain(|c| !dates.contains(&c.datetime.date()));
+            counts.extend(locations.get(&location_id).into_iter().flatten().cloned());
+            counts.sort_by_key(|c| c.datetime);
+            num_files += write_parquet(&path, INDIVIDUAL_SCHEMA, individual_columns(&counts))?;
+        }
+
+        let path = partition(dir, "csv", year, month).join("individual.csv");
+        merge_csv(
+            &path,
+            &["location_id", "datetime", "mode", "direction", "count"],
+            dates,
+            locations.values().flatten().flat_map(|c| {
+                let datetime = c.datetime.format("%Y-%m-%dT%H:%M:%S").to_string();
+                [
+                    ("total", "all", c.total),
+                    ("ped", "in", c.ped_in),
+                    ("ped", "out", c.ped_out),
+                    ("bike", "in", c.bike_in),
+                    ("bike", "out", c.bike_out),
+                ]
+                .into_iter()
+                .filter_map(move |(mode, direction, count)| {
+                    count.map(|v| {
+                        vec![
+                            c.location_id.to_string(),
+                            datetime.clone(),
+                            mode.to_string(),
+                            direction.to_string(),
+                            v.to_string(),
+                        ]
+                    })
+                })
+            }),
+        )?;
+        num_files += 1;
+
+        let mut locations = daily.remove(&(year, month)).unwrap_or_default();
+        for counts in locations.values_mut() {
+            counts.sort_by_key(|c| c.date);
+        }
+        let parquet_dir = partition(dir, "parquet/daily", year, month);
+        for location_id in partition_locations(&parquet_dir, locations.keys())? {
+            let path = parquet_dir.join(format!("location={location_id}/counts.parquet"));
+            let mut counts = read_daily(&path)?;
+            counts.retain(|c| !dates.contains(&c.date));
+            counts.extend(locations.get(&location_id).into_iter().flatten().cloned());
+            counts.sort_by_key(|c| c.date);
+            num_files += write_parquet(&path, DAILY_SCHEMA, daily_columns(&counts))?;
+        }
+
+        let path = partition(dir, "csv", year, month).join("daily.csv");
+        merge_csv(
+            &path,
+            &["location_id", "date", "mode", "count"],
+            dates,
+            locations.values().flatten().flat_map(|c| {
+                let date = c.date.format("%Y-%m-%d").to_string();
+                [
+                    ("total", c.total),
+                    ("ped", c.total_ped),
+                    ("bike", c.total_bike),
+                ]
+                .into_iter()
+                .filter_map(move |(mode, count)| {
+                    count.map(|v| {
+                        vec![
+                            c.location_id.to_string(),
+                            date.clone(),
+                            mode.to_string(),
+                            v.to_string(),
+                        ]
+                    })
+                })
+            }),
+        )?;
+        num_files += 1;
+    }
+
+    Ok(num_files)
+}
+
+fn partition(dir: &Path, kind: &str, year: i32, month: u32) -> PathBuf {
+    dir.join(kind)
+        .join(format!("year={year}"))
+        .join(format!("month={month:02}"))
+}
+
+// The locations with a file in the Parquet partition `dir`, along with `new` ones, in order.
+fn partition_locations<'a>(
+    dir: &Path,
+    new: impl Iterator<Item = &'a i32>,
+) -> Result<BTreeSet<i32>, ExportError> {
+    let mut locations = new.copied().collect::<BTreeSet<_>>();
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            if let Some(id) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("location="))
+                .and_then(|id| id.parse().ok())
+            {
+                locations.insert(id);
+            }
+        }
+    }
+    Ok(locations)
+}
+
+fn individual_columns(counts: &[IndividualCount]) -> Vec<Column> {
+    vec![
+        Column::Int32(counts.iter().map(|c| Some(c.location_id)).collect()),
+        Column::Int64(
+            counts
+                .iter()
+                .map(|c| Some(timestamp_millis(c.datetime)))
+                .collect(),
+        ),
+        Column::Int32(counts.iter().map(|c| c.total).collect()),
+        Column::Int32(counts.iter().map(|c| c.ped_in).collect()),
+        Column::Int32(counts.iter().map(|c| c.ped_out).collect()),
+        Column::Int32(counts.iter().map(|c| c.bike_in).collect()),
+        Column::Int32(counts.iter().map(|c| c.bike_out).collect()),
+    ]
+}
+
+fn daily_columns(counts: &[AggregatedCount]) -> Vec<Column> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let mut columns = vec![
+        Column::Int32(counts.iter().map(|c| Some(c.location_id)).collect()),
+        Column::Int32(
+            counts
+                .iter()
+                .map(|c| Some((c.date - epoch).num_days() as i32))
+                .collect(),
+        ),
+        Column::Int32(counts.iter().map(|c| c.total_ped).collect()),
+        Column::Int32(counts.iter().map(|c| c.total_bike).collect()),
+        Column::Int32(counts.iter().map(|c| c.total).collect()),
+    ];
+    for peak in [
+        |c: &AggregatedCount| c.am_peak.clone(),
+        |c: &AggregatedCount| c.pm_peak.clone(),
+    ] {
+        let peaks = counts.iter().map(peak).collect::<Vec<_>>();
+        let field = |f: fn(&PeakHour) -> Option<i32>| {
+            Column::Int32(peaks.iter().map(|p| p.as_ref().and_then(f)).collect())
+        };
+        columns.extend([
+            Column::Int64(
+                peaks
+                    .iter()
+                    .map(|p| p.as_ref().map(|p| timestamp_millis(p.start)))
+                    .collect(),
+            ),
+            field(|p| p.total),
+            field(|p| p.ped),
+            field(|p| p.bike),
+            Column::Double(
+                peaks
+                    .iter()
+                    .map(|p| p.as_ref().and_then(|p| p.phf))
+                    .collect(),
+            ),
+        ]);
+    }
+    columns
+}
+
+// Read the individual counts exported to `path`, if it exists.
+fn read_individual(path: &Path) -> Result<Vec<IndividualCount>, ExportError> {
+    let mut counts = vec![];
+    for row in read_parquet(path)? {
+        counts.push(IndividualCount {
+            location_id: required(int(&row, 0), path)?,
+            datetime: required(long(&row, 1).and_then(from_millis), path)?,
+            total: int(&row, 2),
+            ped_in: int(&row, 3),
+            ped_out: int(&row, 4),
+            bike_in: int(&row, 5),
+            bike_out: int(&row, 6),
+        });
+    }
+    Ok(counts)
+}
+
+// Read the daily counts exported to `path`, if it exists.
+fn read_daily(path: &Path) -> Result<Vec<AggregatedCount>, ExportError> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let peak = |row: &[Field], i: usize| {
+        long(row, i).and_then(from_millis).map(|start| PeakHour {
+            start,
+            total: int(row, i + 1),
+            ped: int(row, i + 2),
+            bike: int(row, i + 3),
+            phf: double(row, i + 4),
+        })
+    };
+    let mut counts = vec![];
+    for row in read_parquet(path)? {
+        let days = required(int(&row, 1), path)?;
+        counts.push(AggregatedCount {
+            location_id: required(int(&row, 0), path)?,
+            date: epoch + Duration::days(days.into()),
+            total_ped: int(&row, 2),
+            total_bike: int(&row, 3),
+            total: int(&row, 4),
+            am_peak: peak(&row, 5),
+            pm_peak: peak(&row, 10),
+        });
+    }
+    Ok(counts)
+}
+
+// The fields of each row of the Parquet file at `path`, or none if it doesn't exist.
+fn read_parquet(path: &Path) -> Result<Vec<Vec<Field>>, ExportError> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let mut rows = vec![];
+    for row in reader.get_row_iter(None)? {
+        rows.push(row?.into_columns().into_iter().map(|(_, f)| f).collect());
+    }
+    Ok(rows)
+}
+
+fn int(row: &[Field], i: usize) -> Option<i32> {
+    match row.get(i) {
+        Some(Field::Int(v) | Field::Date(v)) => Some(*v),
+        _ => None,
+    }
+}
+
+fn long(row: &[Field], i: usize) -> Option<i64> {
+    match row.get(i) {
+        Some(Field::Long(v) | Field::TimestampMillis(v)) => Some(*v),
+        _ => None,
+    }
+}
+
+fn double(row: &[Field], i: usize) -> Option<f64> {
+    match row.get(i) {
+        Some(Field::Double(v)) => Some(*v),
+        _ => None,
+    }
+}
+
+fn required<T>(value: Option<T>, path: &Path) -> Result<T, ExportError> {
+    value.ok_or_else(|| {
+        ExportError::Parquet(ParquetError::General(format!(
+            "{} has a row missing a required value",
+            path.display()
+        )))
+    })
+}
+
+fn from_millis(millis: i64) -> Option<NaiveDateTime> {
+    DateTime::from_timestamp_millis(millis).map(|v| v.naive_utc())
+}
+
+// Timestamps are stored as local time, as they are in the source data.
+fn timestamp_millis(datetime: NaiveDateTime) -> i64 {
+    datetime.and_utc().timestamp_millis()
+}
+
+// Write `columns` to the Parquet file at `path`, returning the number of files written: none, if
+// there are no rows, in which case any previous file is removed.
+fn write_parquet(path: &Path, schema: &str, columns: Vec<Column>) -> Result<usize, ExportError> {
+    let rows = match columns.first() {
+        Some(Column::Int32(v)) => v.len(),
+        Some(Column::Int64(v)) => v.len(),
+        Some(Column::Double(v)) => v.len(),
+        None => 0,
+    };
+    if rows == 0 {
+        if path.exists() {
+            fs::remove_file(path)?;
+            if let Some(parent) = path.parent() {
+                fs::remove_dir(parent).ok();
+            }
+        }
+        return Ok(0);
+    }
+
+    let schema = Arc::new(parse_message_type(schema)?);
+    let props = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+
+    let tmp_path = path.with_extension("parquet.tmp");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = SerializedFileWriter::new(File::create(&tmp_path)?, schema, props)?;
+    let mut row_group = writer.next_row_group()?;
+    for column in columns {
+        let Some(mut col) = row_group.next_column()? else {
+            break;
+        };
+        match column {
+            Column::Int32(v) => write_column::<Int32Type>(&mut col, &v)?,
+            Column::Int64(v) => write_column::<Int64Type>(&mut col, &v)?,
+            Column::Double(v) => write_column::<DoubleType>(&mut col, &v)?,
+        }
+        col.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+
+    fs::rename(&tmp_path, path)?;
+    Ok(1)
+}
+
+fn write_column<T: DataType>(
+    col: &mut SerializedColumnWriter,
+    values: &[Option<T::T>],
+) -> Result<(), ParquetError> {
+    let writer = col.typed::<T>();
+    let present = values.iter().flatten().cloned().collect::<Vec<_>>();
+    if writer.get_descriptor().max_def_level() == 0 {
+        writer.write_batch(&present, None, None)?;
+    } else {
+        let def_levels = values
+            .iter()
+            .map(|v| v.is_some() as i16)
+            .collect::<Vec<_>>();
+        writer.write_batch(&present, Some(&def_levels), None)?;
+    }
+    Ok(())
+}
+
+// Write the CSV at `path`, keeping the rows already in it except those on `dates`, and adding
+// `rows`. Rows start with the location id and date or datetime, and are in that order both in the
+// file and in `rows`, so the two are merged a row at a time rather than read into memory.
+fn merge_csv(
+    path: &Path,
+    header: &[&str],
+    dates: &BTreeSet<NaiveDate>,
+    rows: impl Iterator<Item = Vec<String>>,
+) -> Result<(), ExportError> {
+    let tmp_path = path.with_extension("csv.tmp");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut wtr = csv::Writer::from_path(&tmp_path)?;
+    wtr.write_record(header)?;
+    let mut rows = rows.peekable();
+    if path.exists() {
+        let mut rdr = csv::Reader::from_path(path)?;
+        for record in rdr.records() {
+            let record = record?;
+            let when = record.get(1).unwrap_or_default();
+            if when
+                .get(..10)
+                .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+                .is_some_and(|d| dates.contains(&d))
+            {
+                continue;
+            }
+            let key = row_key(record.get(0).unwrap_or_default(), when);
+            while let Some(row) = rows.next_if(|row| row_key(&row[0], &row[1]) <= key) {
+                wtr.write_record(&row)?;
+            }
+            wtr.write_record(&record)?;
+        }
+    }
+    for row in rows {
+        wtr.write_record(&row)?;
+    }
+    wtr.flush()?;
+    drop(wtr);
+
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// The order of a CSV row: by location, then date or datetime.
+fn row_key<'a>(location_id: &str, when: &'a str) -> (i32, &'a str) {
+    (location_id.parse().unwrap_or_default(), when)
+}
@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use chrono::prelude::*;
+use oracle::sql_type::Timestamp;
+use oracle::{Connection, Error as OracleError};
+
+use crate::report::TableCounts;
+
+// A named group of locations with similar patterns (e.g. "urban trail"), whose factors are the
+// average of their members' factors.
+#[derive(Debug, Clone)]
+pub struct FactorGroup {
+    pub name: String,
+    pub location_ids: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FactorType {
+    // period is the month, 1-12
+    Month,
+    // period is the ISO day of the week, 1 (Monday) - 7 (Sunday)
+    DayOfWeek,
+}
+
+impl fmt::Display for FactorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FactorType::Month => write!(f, "MONTH"),
+            FactorType::DayOfWeek => write!(f, "DOW"),
+        }
+    }
+}
+
+// Factors for one location or group: AADT divided by the average daily volume of the period.
+pub type Factors = BTreeMap<(FactorType, u32), f64>;
+
+// Parse factor groups from the format "name:id,id,...;name:id,...".
+pub fn parse_groups(groups: &str) -> Result<Vec<FactorGroup>, String> {
+    let mut parsed = vec![];
+    for group in groups.split(';').filter(|g| !g.trim().is_empty()) {
+        let Some((name, ids)) = group.split_once(':') else {
+            return Err(format!(
+                "Factor group \"{group}\" is not in the form name:id,id,..."
+            ));
+        };
+        let location_ids = ids
+            .split(',')
+            .map(|id| id.trim().parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid location id in factor group \"{name}\": {e}"))?;
+        parsed.push(FactorGroup {
+            name: name.trim().to_string(),
+            location_ids,
+        });
+    }
+    Ok(parsed)
+}
+
+// Get the daily totals in TBLHEADER, by location, leaving out days flagged in TBLANOMALY, which
+// are more likely a counter problem than a real change in volume.
+pub fn get_daily_totals(
+    conn: &Connection,
+) -> Result<HashMap<i32, Vec<(NaiveDate, i32)>>, OracleError> {
+    let rows = conn.query_as::<(i32, Timestamp, i32)>(
+        "select h.locationid, h.countdate, h.total from TBLHEADER h where h.total is not null and not exists (select 1 from TBLANOMALY a where a.locationid = h.locationid and a.countdate = h.countdate)",
+        &[],
+    )?;
+    let mut totals: HashMap<i32, Vec<(NaiveDate, i32)>> = HashMap::new();
+    for row in rows {
+        let (location_id, countdate, total) = row?;
+        if let Some(date) =
+            NaiveDate::from_ymd_opt(countdate.year(), countdate.month(), countdate.day())
+        {
+            totals.entry(location_id).or_default().push((date, total));
+        }
+    }
+    Ok(totals)
+}
+
+// Calculate monthly and day-of-week factors for a location from its daily totals.
+//
+// Days with a total of 0 are left out, as they're almost always a dead counter rather than a day
+// without anyone passing it. AADT is the average of the 12 monthly average daily volumes (so
+// months with more data don't count for more), and each monthly factor is AADT divided by the
+// average daily volume of its month. Day-of-week factors are found within each month (the
+// month's average daily volume divided by that of the day of the week in it) and then averaged
+// over the months, so that a day of the week with more data in busy months doesn't look busier.
+// Locations without data in every month get no factors.
+pub fn location_factors(totals: &[(NaiveDate, i32)]) -> Option<Factors> {
+    let mut months: BTreeMap<u32, (f64, u32)> = BTreeMap::new();
+    let mut days: BTreeMap<(u32, u32), (f64, u32)> = BTreeMap::new();
+    for (date, total) in totals.iter().filter(|(_, total)| *total > 0) {
+        let month = months.entry(date.month()).or_default();
+        month.0 += *total as f64;
+        month.1 += 1;
+        let day = days
+            .entry((date.month(), date.weekday().number_from_monday()))
+            .or_default();
+        day.0 += *total as f64;
+        day.1 += 1;
+    }
+    if months.len() < 12 {
+        return None;
+    }
+
+    let average = |(sum, n): &(f64, u32)| sum / *n as f64;
+    let aadt = months.values().map(average).sum::<f64>() / 12.0;
+
+    let mut factors = Factors::new();
+    for (month, sum) in &months {
+        factors.insert((FactorType::Month, *month), aadt / average(sum));
+    }
+    let mut day_factors: BTreeMap<u32, (f64, u32)> = BTreeMap::new();
+    for ((month, day), sum) in &days {
+        let factor = day_factors.entry(*day).or_default();
+        factor.0 += average(&months[month]) / average(sum);
+        factor.1 += 1;
+    }
+    for (day, sum) in &day_factors {
+        factors.insert((FactorType::DayOfWeek, *day), average(sum));
+    }
+    Some(factors)
+}
+
+// Average the factors of a group's member locations.
+pub fn group_factors(group: &FactorGroup, locations: &HashMap<i32, Factors>) -> Factors {
+    let mut sums: BTreeMap<(FactorType, u32), (f64, u32)> = BTreeMap::new();
+    for factors in group.location_ids.iter().filter_map(|id| locations.get(id)) {
+        for (key, factor) in factors {
+            let sum = sums.entry(*key).or_default();
+            sum.0 += factor;
+            sum.1 += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(key, (sum, n))| (key, sum / n as f64))
+        .collect()
+}
+
+// Recalculate all factors from TBLHEADER and replace the contents of TBLFACTORS with them.
+// Location factors have a null FACTORGROUP and group factors a null LOCATIONID.
+pub fn refresh(conn: &Connection, groups: &[FactorGroup]) -> Result<TableCounts, OracleError> {
+    let totals = get_daily_totals(conn)?;
+    let locations = totals
+        .iter()
+        .filter_map(|(id, totals)| location_factors(totals).map(|f| (*id, f)))
+        .collect::<HashMap<_, _>>();
+
+    let mut counts = TableCounts {
+        deleted: conn.execute("delete from TBLFACTORS", &[])?.row_count()?,
+        inserted: 0,
+    };
+    let sql = "insert into TBLFACTORS (factorgroup, locationid, factortype, period, factor) values (:1, :2, :3, :4, :5)";
+    for (location_id, factors) in &locations {
+        for ((factor_type, period), factor) in factors {
+            conn.execute(
+                sql,
+                &[
+                    &None::<String>,
+                    &Some(*location_id),
+                    &factor_type.to_string(),
+                    period,
+                    factor,
+                ],
+            )?;
+            counts.inserted += 1;
+        }
+    }
+    for group in groups {
+        for ((factor_type, period), factor) in group_factors(group, &locations) {
+            conn.execute(
+                sql,
+                &[
+                    &Some(group.name.as_str()),
+                    &None::<i32>,
+                    &factor_type.to_string(),
+                    &period,
+                    &factor,
+                ],
+            )?;
+            counts.inserted += 1;
+        }
+    }
+    conn.commit()?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A year of daily totals, `total(date)` for each date it returns one for.
+    fn year(total: impl Fn(NaiveDate) -> Option<i32>) -> Vec<(NaiveDate, i32)> {
+        NaiveDate::from_ymd_opt(2023, 1, 1)
+            .unwrap()
+            .iter_days()
+            .take_while(|d| d.year() == 2023)
+            .filter_map(|d| total(d).map(|t| (d, t)))
+            .collect()
+    }
+
+    fn assert_all_near(factors: &Factors, factor_type: FactorType, expected: f64) {
+        for ((t, period), factor) in factors {
+            if *t == factor_type {
+                assert!(
+                    (factor - expected).abs() < 1e-9,
+                    "{t} {period}: {factor} != {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn days_with_a_total_of_zero_are_left_out() {
+        // The same volume every day, but a dead counter for half of June.
+        let totals = year(|d| {
+            Some(if d.month() == 6 && d.day() <= 15 {
+                0
+            } else {
+                100
+            })
+        });
+        let factors = location_factors(&totals).unwrap();
+        assert_all_near(&factors, FactorType::Month, 1.0);
+        assert_all_near(&factors, FactorType::DayOfWeek, 1.0);
+    }
+
+    #[test]
+    fn day_of_week_factors_are_found_within_months() {
+        // Busier in summer, with no difference between days of the week, but with Mondays missing
+        // from July. Pooled over the year, Mondays would look quieter than other days.
+        let totals = year(|d| {
+            let missing = d.month() == 7 && d.weekday() == Weekday::Mon;
+            (!missing).then_some(d.month() as i32 * 100)
+        });
+        let factors = location_factors(&totals).unwrap();
+        assert_eq!(
+            factors
+                .keys()
+                .filter(|(t, _)| *t == FactorType::DayOfWeek)
+                .count(),
+            7
+        );
+        assert_all_near(&factors, FactorType::DayOfWeek, 1.0);
+    }
+
+    #[test]
+    fn locations_without_every_month_have_no_factors() {
+        let totals = year(|d| (d.month() != 2).then_some(100));
+        assert!(location_factors(&totals).is_none());
+    }
+}
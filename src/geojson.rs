@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use chrono::prelude::*;
+use chrono::{Duration, Months};
+use oracle::sql_type::Timestamp;
+use oracle::{Connection, Error as OracleError};
+use serde_json::{json, Value};
+
+use crate::db::to_timestamp;
+
+// Number of days of daily totals, up to the most recent date, to include for each location.
+const DAILY_DAYS: i64 = 31;
+
+// Number of months of monthly totals, including that of the most recent date.
+const MONTHLY_MONTHS: u32 = 12;
+
+// A counter location, for placing it on the map.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub location_id: i32,
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug)]
+pub enum GeoJsonError {
+    Csv(csv::Error),
+    Location(String),
+    Oracle(OracleError),
+    Io(io::Error),
+}
+
+impl fmt::Display for GeoJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoJsonError::Csv(e) => write!(f, "{e}"),
+            GeoJsonError::Location(e) => write!(f, "Invalid location: {e}"),
+            GeoJsonError::Oracle(e) => write!(f, "{e}"),
+            GeoJsonError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<csv::Error> for GeoJsonError {
+    fn from(e: csv::Error) -> Self {
+        GeoJsonError::Csv(e)
+    }
+}
+
+impl From<OracleError> for GeoJsonError {
+    fn from(e: OracleError) -> Self {
+        GeoJsonError::Oracle(e)
+    }
+}
+
+impl From<io::Error> for GeoJsonError {
+    fn from(e: io::Error) -> Self {
+        GeoJsonError::Io(e)
+    }
+}
+
+// Read locations from a CSV with a header and columns locationid, name, latitude, longitude.
+pub fn read_locations(path: &str) -> Result<Vec<Location>, GeoJsonError> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let mut locations = vec![];
+    for result in rdr.records() {
+        let record = result?;
+        if record.len() != 4 {
+            return Err(GeoJsonError::Location(format!(
+                "expected 4 fields, found {}",
+                record.len()
+            )));
+        }
+        let invalid = |i: usize| GeoJsonError::Location(format!("could not parse {}", &record[i]));
+        let number = |i: usize| record[i].trim().parse::<f64>().map_err(|_| invalid(i));
+        locations.push(Location {
+            location_id: record[0].trim().parse::<i32>().map_err(|_| invalid(0))?,
+            name: record[1].trim().to_string(),
+            latitude: number(2)?,
+            longitude: number(3)?,
+        });
+    }
+    Ok(locations)
+}
+
+// (ped, bike, total) for a day or month.
+pub type Totals = (Option<i32>, Option<i32>, Option<i32>);
+
+// Write a GeoJSON FeatureCollection with a point feature for each location, with its daily totals
+// for the month up to `latest` and its monthly totals for the year up to `latest`, from TBLHEADER.
+pub fn export(
+    conn: &Connection,
+    locations: &[Location],
+    latest: NaiveDate,
+    path: &str,
+) -> Result<(), GeoJsonError> {
+    let rows = conn.query_as::<(i32, Timestamp, Option<i32>, Option<i32>, Option<i32>)>(
+        "select locationid, countdate, totalped, totalbike, total from TBLHEADER where countdate >= :1 and countdate <= :2",
+        &[&to_timestamp(first_date(latest)), &to_timestamp(latest)],
+    )?;
+    let mut totals = vec![];
+    for row in rows {
+        let (location_id, countdate, ped, bike, total) = row?;
+        if let Some(date) =
+            NaiveDate::from_ymd_opt(countdate.year(), countdate.month(), countdate.day())
+        {
+            totals.push((location_id, date, (ped, bike, total)));
+        }
+    }
+    write(&totals, locations, latest, path)
+}
+
+// The first date whose totals are needed for the GeoJSON up to `latest`.
+fn first_date(latest: NaiveDate) -> NaiveDate {
+    first_month(latest).min(latest - Duration::days(DAILY_DAYS - 1))
+}
+
+fn first_month(latest: NaiveDate) -> NaiveDate {
+    latest.with_day(1).unwrap() - Months::new(MONTHLY_MONTHS - 1)
+}
+
+// Write the GeoJSON for `latest` from the daily (ped, bike, total) of each location and date.
+//
+// The webmap (<https://www.dvrpc.org/webmaps/permbikeped/>) requires missing data to be encoded
+// as 0, so that's done here rather than in the database.
+pub fn write(
+    totals: &[(i32, NaiveDate, Totals)],
+    locations: &[Location],
+    latest: NaiveDate,
+    path: &str,
+) -> Result<(), GeoJsonError> {
+    let first_month = first_month(latest);
+    let first_day = latest - Duration::days(DAILY_DAYS - 1);
+
+    let mut daily: BTreeMap<i32, BTreeMap<NaiveDate, Totals>> = BTreeMap::new();
+    let mut monthly: BTreeMap<i32, BTreeMap<(i32, u32), Totals>> = BTreeMap::new();
+    for &(location_id, date, (ped, bike, total)) in totals {
+        if date > latest {
+            continue;
+        }
+        if date >= first_day {
+            daily
+                .entry(location_id)
+                .or_default()
+                .insert(date, (ped, bike, total));
+        }
+        if date >= first_month {
+            let month = monthly
+                .entry(location_id)
+                .or_default()
+                .entry((date.year(), date.month()))
+                .or_insert((None, None, None));
+            month.0 = add(month.0, ped);
+            month.1 = add(month.1, bike);
+            month.2 = add(month.2, total);
+        }
+    }
+
+    let features = locations
+        .iter()
+        .map(|location| {
+            let daily = daily
+                .remove(&location.location_id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(date, totals)| {
+                    let mut v = encode(totals);
+                    v["date"] = json!(date.format("%Y-%m-%d").to_string());
+                    v
+                })
+                .collect::<Vec<_>>();
+            let monthly = monthly
+                .remove(&location.location_id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|((year, month), totals)| {
+                    let mut v = encode(totals);
+                    v["month"] = json!(format!("{year}-{month:02}"));
+                    v
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [location.longitude, location.latitude],
+                },
+                "properties": {
+                    "locationid": location.location_id,
+                    "name": location.name,
+                    "daily": daily,
+                    "monthly": monthly,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let collection = json!({
+        "type": "FeatureCollection",
+        "features": features,
+    });
+
+    // Write to a temporary file first, so the webmap never picks up a partially-written one.
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, collection.to_string())?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn add(a: Option<i32>, b: Option<i32>) -> Option<i32> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+// Encode totals the way the webmap expects: missing data as 0.
+fn encode((ped, bike, total): Totals) -> Value {
+    json!({
+        "ped": ped.unwrap_or(0),
+        "bike": bike.unwrap_or(0),
+        "total": total.unwrap_or(0),
+    })
+}
@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::parser::{IndividualCount, ONE_WAY_BIKE_LANES};
+
+// A channel stuck at zero this long while the opposite direction counts is considered dead.
+const MIN_ZERO_RUN_HOURS: i64 = 24;
+
+// The same non-zero value repeated for this long is considered stuck.
+const MIN_CONSTANT_RUN_HOURS: i64 = 6;
+
+// Change in a direction's share of its mode's daily volume, compared to the median of the
+// preceding days, that is considered a sudden change in directional ratio.
+const MAX_SHARE_CHANGE: f64 = 0.25;
+const SHARE_HISTORY_DAYS: usize = 7;
+const MIN_SHARE_HISTORY_DAYS: usize = 3;
+// Days with less volume than this for the mode are too noisy for the ratio to mean anything.
+const MIN_SHARE_VOLUME: i32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Channel {
+    PedIn,
+    PedOut,
+    BikeIn,
+    BikeOut,
+}
+
+impl Channel {
+    fn value(&self, count: &IndividualCount) -> Option<i32> {
+        match self {
+            Channel::PedIn => count.ped_in,
+            Channel::PedOut => count.ped_out,
+            Channel::BikeIn => count.bike_in,
+            Channel::BikeOut => count.bike_out,
+        }
+    }
+
+    fn opposite(&self) -> Channel {
+        match self {
+            Channel::PedIn => Channel::PedOut,
+            Channel::PedOut => Channel::PedIn,
+            Channel::BikeIn => Channel::BikeOut,
+            Channel::BikeOut => Channel::BikeIn,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::PedIn => write!(f, "ped_in"),
+            Channel::PedOut => write!(f, "ped_out"),
+            Channel::BikeIn => write!(f, "bike_in"),
+            Channel::BikeOut => write!(f, "bike_out"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    NoData,
+    StuckAtZero,
+    ConstantValue(i32),
+    RatioChange { from: f64, to: f64 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NoData => write!(f, "no data"),
+            Problem::StuckAtZero => write!(f, "stuck at zero while opposite direction is active"),
+            Problem::ConstantValue(v) => write!(f, "stuck at constant value {v}"),
+            Problem::RatioChange { from, to } => write!(
+                f,
+                "share of mode's volume changed from {:.0}% to {:.0}%",
+                from * 100.0,
+                to * 100.0
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthIssue {
+    pub location_id: i32,
+    pub channel: Channel,
+    pub problem: Problem,
+    pub start: NaiveDateTime,
+    pub duration: Duration,
+}
+
+impl fmt::Display for HealthIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.duration.num_days();
+        let hours = self.duration.num_hours() - days * 24;
+        write!(
+            f,
+            "Location {}, {}: {} from {} for {} day(s) {} hour(s).",
+            self.location_id,
+            self.channel,
+            self.problem,
+            self.start.format("%Y-%m-%d %H:%M"),
+            days,
+            hours
+        )
+    }
+}
+
+// The channels each location is expected to report. The one-way bike lanes only count in one
+// direction (and never report pedestrians).
+fn channels(location_id: i32) -> &'static [Channel] {
+    if ONE_WAY_BIKE_LANES.contains(&location_id) {
+        &[Channel::BikeIn]
+    } else {
+        &[
+            Channel::PedIn,
+            Channel::PedOut,
+            Channel::BikeIn,
+            Channel::BikeOut,
+        ]
+    }
+}
+
+// The inbound and outbound channel of each mode.
+const MODES: [(Channel, Channel); 2] = [
+    (Channel::PedIn, Channel::PedOut),
+    (Channel::BikeIn, Channel::BikeOut),
+];
+
+// A run of consecutive bins with the same value of a channel.
+#[derive(Debug)]
+struct Run {
+    value: Option<i32>,
+    start: NaiveDateTime,
+    // The last bin of the run.
+    end: NaiveDateTime,
+    bins: usize,
+    // Whether the opposite direction counted anything during the run.
+    opposite_active: bool,
+}
+
+// What's known so far of one location's series of counts.
+#[derive(Debug)]
+struct Series {
+    location_id: i32,
+    first: NaiveDateTime,
+    last: NaiveDateTime,
+    // The shortest time between bins so far, i.e. the resolution of the series.
+    interval: Option<Duration>,
+    // The run each channel is in, and the channels that have had any data.
+    runs: BTreeMap<Channel, Run>,
+    has_data: BTreeSet<Channel>,
+    // Daily totals in each direction, by mode (keyed by its inbound channel).
+    daily: BTreeMap<Channel, BTreeMap<NaiveDate, (i32, i32)>>,
+    issues: Vec<HealthIssue>,
+}
+
+impl Series {
+    fn new(location_id: i32, first: NaiveDateTime) -> Self {
+        Self {
+            location_id,
+            first,
+            last: first,
+            interval: None,
+            runs: BTreeMap::new(),
+            has_data: BTreeSet::new(),
+            daily: BTreeMap::new(),
+            issues: vec![],
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval.unwrap_or(Duration::hours(1))
+    }
+
+    fn add(&mut self, count: &IndividualCount) {
+        let gap = count.datetime - self.last;
+        if gap > Duration::zero() {
+            self.interval = Some(self.interval.map_or(gap, |v| v.min(gap)));
+        }
+        self.last = count.datetime;
+
+        let expected = channels(self.location_id);
+        for channel in expected {
+            let value = channel.value(count);
+            if value.is_some() {
+                self.has_data.insert(*channel);
+            }
+            let opposite_active = channel.opposite().value(count).unwrap_or(0) > 0;
+            match self.runs.get_mut(channel) {
+                Some(run) if run.value == value => {
+                    run.end = count.datetime;
+                    run.bins += 1;
+                    run.opposite_active |= opposite_active;
+                }
+                _ => {
+                    let run = Run {
+                        value,
+                        start: count.datetime,
+                        end: count.datetime,
+                        bins: 1,
+                        opposite_active,
+                    };
+                    if let Some(run) = self.runs.insert(*channel, run) {
+                        self.close(*channel, run);
+                    }
+                }
+            }
+        }
+
+        for (inbound, outbound) in MODES {
+            if expected.contains(&inbound) && expected.contains(&outbound) {
+                let (i, o) = self
+                    .daily
+                    .entry(inbound)
+                    .or_default()
+                    .entry(count.datetime.date())
+                    .or_insert((0, 0));
+                *i += inbound.value(count).unwrap_or(0);
+                *o += outbound.value(count).unwrap_or(0);
+            }
+        }
+    }
+
+    // Record the problem with a run that has ended, if any.
+    fn close(&mut self, channel: Channel, run: Run) {
+        let duration = run.end - run.start + self.interval();
+        let check_opposite = channels(self.location_id).contains(&channel.opposite());
+        let problem = match run.value {
+            Some(0)
+                if check_opposite
+                    && run.opposite_active
+                    && duration >= Duration::hours(MIN_ZERO_RUN_HOURS) =>
+            {
+                Problem::StuckAtZero
+            }
+            Some(v)
+                if v != 0
+                    && run.bins > 1
+                    && duration >= Duration::hours(MIN_CONSTANT_RUN_HOURS) =>
+            {
+                Problem::ConstantValue(v)
+            }
+            _ => return,
+        };
+        self.issues.push(HealthIssue {
+            location_id: self.location_id,
+            channel,
+            problem,
+            start: run.start,
+            duration,
+        });
+    }
+
+    fn finish(mut self) -> Vec<HealthIssue> {
+        for (channel, run) in std::mem::take(&mut self.runs) {
+            if self.has_data.contains(&channel) {
+                self.close(channel, run);
+            } else {
+                self.issues.push(HealthIssue {
+                    location_id: self.location_id,
+                    channel,
+                    problem: Problem::NoData,
+                    start: self.first,
+                    duration: self.last - self.first + self.interval(),
+                });
+            }
+        }
+        self.issues.sort_by_key(|i| (i.channel, i.start));
+        for (inbound, outbound) in MODES {
+            if let Some(daily) = self.daily.remove(&inbound) {
+                self.issues
+                    .extend(ratio_changes(self.location_id, daily, inbound, outbound));
+            }
+        }
+        self.issues
+    }
+}
+
+// Checks each location's series of counts for dead, stuck, or one-directional channels. An export
+// is added a chunk at a time, with runs and daily totals kept from one chunk to the next, so a
+// problem spanning chunks is reported once, from its start; the problems are found by `finish`.
+#[derive(Debug, Default)]
+pub struct Monitor {
+    series: BTreeMap<i32, Series>,
+}
+
+impl Monitor {
+    // Add the next counts of an export, which come after those already added.
+    pub fn add(&mut self, counts: &[IndividualCount]) {
+        let mut by_location: BTreeMap<i32, Vec<&IndividualCount>> = BTreeMap::new();
+        for count in counts {
+            by_location
+                .entry(count.location_id)
+                .or_default()
+                .push(count);
+        }
+        for (location_id, mut chunk) in by_location {
+            chunk.sort_by_key(|c| c.datetime);
+            let series = self
+                .series
+                .entry(location_id)
+                .or_insert_with(|| Series::new(location_id, chunk[0].datetime));
+            for count in chunk {
+                series.add(count);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<HealthIssue> {
+        self.series.into_values().flat_map(Series::finish).collect()
+    }
+}
+
+// Find days where one direction's share of the mode's daily volume changes suddenly from what it
+// was over the preceding days. The direction whose share dropped is reported, and the duration
+// is how long the new ratio persisted.
+fn ratio_changes(
+    location_id: i32,
+    daily: BTreeMap<NaiveDate, (i32, i32)>,
+    inbound: Channel,
+    outbound: Channel,
+) -> Vec<HealthIssue> {
+    let shares = daily
+        .into_iter()
+        .filter(|(_, (i, o))| i + o >= MIN_SHARE_VOLUME)
+        .map(|(date, (i, o))| (date, i as f64 / (i + o) as f64))
+        .collect::<Vec<_>>();
+
+    let mut issues = vec![];
+    let mut i = MIN_SHARE_HISTORY_DAYS;
+    while i < shares.len() {
+        let mut history = shares[i.saturating_sub(SHARE_HISTORY_DAYS)..i]
+            .iter()
+            .map(|(_, s)| *s)
+            .collect::<Vec<_>>();
+        history.sort_by(|a, b| a.total_cmp(b));
+        let before = history[history.len() / 2];
+        let (date, share) = shares[i];
+
+        if (share - before).abs() <= MAX_SHARE_CHANGE {
+            i += 1;
+            continue;
+        }
+
+        // How long does the new ratio last?
+        let persisted = shares[i..]
+            .iter()
+            .take_while(|(_, s)| (s - before).abs() > MAX_SHARE_CHANGE)
+            .count();
+        let (channel, from, to) = if share < before {
+            (inbound, before, share)
+        } else {
+            (outbound, 1.0 - before, 1.0 - share)
+        };
+        issues.push(HealthIssue {
+            location_id,
+            channel,
+            problem: Problem::RatioChange { from, to },
+            start: date.and_hms_opt(0, 0, 0).unwrap(),
+            duration: shares[i + persisted - 1].0 - date + Duration::days(1),
+        });
+        i += persisted;
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, day).unwrap()
+    }
+
+    // Two weeks of hourly counts at Bartram's Garden, with bike_in stuck at zero from the 3rd to
+    // the 12th, and four times as many pedestrians inbound from the 9th.
+    fn two_weeks() -> Vec<IndividualCount> {
+        let mut counts = vec![];
+        for day in 1..=14 {
+            for hour in 0..24 {
+                let v = (hour % 5 + 1) as i32;
+                let ped_in = if day >= 9 { 4 * v } else { v };
+                let bike_in = if (3..=12).contains(&day) { 0 } else { v };
+                counts.push(IndividualCount {
+                    location_id: 16,
+                    datetime: date(day).and_hms_opt(hour, 0, 0).unwrap(),
+                    total: Some(ped_in + v + bike_in + v),
+                    ped_in: Some(ped_in),
+                    ped_out: Some(v),
+                    bike_in: Some(bike_in),
+                    bike_out: Some(v),
+                });
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn problems_spanning_chunks_are_reported_once() {
+        let counts = two_weeks();
+        let mut whole = Monitor::default();
+        whole.add(&counts);
+        let whole = whole.finish();
+        let mut by_day = Monitor::default();
+        for day in counts.chunks(24) {
+            by_day.add(day);
+        }
+        let by_day = by_day.finish();
+        assert_eq!(format!("{whole:?}"), format!("{by_day:?}"));
+
+        let stuck = by_day
+            .iter()
+            .filter(|i| i.problem == Problem::StuckAtZero)
+            .collect::<Vec<_>>();
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].channel, Channel::BikeIn);
+        assert_eq!(stuck[0].start, date(3).and_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(stuck[0].duration, Duration::days(10));
+
+        // Found although the change is on the second day of the last week.
+        let ratio = by_day
+            .iter()
+            .filter(|i| i.channel == Channel::PedOut)
+            .collect::<Vec<_>>();
+        assert_eq!(ratio.len(), 1);
+        assert!(matches!(ratio[0].problem, Problem::RatioChange { .. }));
+        assert_eq!(ratio[0].start, date(9).and_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(ratio[0].duration, Duration::days(6));
+    }
+}
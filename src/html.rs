@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use chrono::prelude::*;
+
+use crate::report::{DailyTotal, RunReport, Status};
+
+const SPARKLINE_WIDTH: f64 = 120.0;
+const SPARKLINE_HEIGHT: f64 = 24.0;
+
+// Render a human-readable summary of an import: a table of each location's monthly totals,
+// compared to the same month last year, with a sparkline of its daily totals, and any
+// validation issues, highlighted.
+pub fn render(report: &RunReport, names: &HashMap<i32, String>) -> String {
+    let mut html = String::new();
+    let title = match &report.date_range {
+        Some(range) => format!("Eco-Counter import: {} to {}", range.start, range.end),
+        None => "Eco-Counter import".to_string(),
+    };
+
+    // Writing to a String can't fail, so the results of write!() are ignored throughout.
+    let _ = write!(
+        html,
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: right; }}
+th:first-child, td:first-child {{ text-align: left; }}
+.failed {{ color: #b00; }}
+.issue {{ background: #fff3cd; }}
+.up {{ color: #070; }}
+.down {{ color: #b00; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+"#,
+        title = escape(&title)
+    );
+
+    match report.status {
+        Status::Failed => {
+            let _ = writeln!(
+                html,
+                r#"<p class="failed"><strong>Import failed:</strong> {}</p>"#,
+                escape(report.error.as_deref().unwrap_or_default())
+            );
+        }
+        Status::Stopped => {
+            let _ = writeln!(
+                html,
+                "<p>Import stopped by shutdown; the file will be imported again on restart.</p>"
+            );
+        }
+        _ => {
+            let _ = writeln!(html, "<p>Import succeeded.</p>");
+        }
+    }
+    let _ = writeln!(
+        html,
+        "<p>File: {}<br>Started: {}</p>",
+        escape(&report.input_file),
+        report.started.format("%Y-%m-%d %H:%M:%S")
+    );
+
+    // Monthly totals by location.
+    let _ = writeln!(
+        html,
+        "<h2>Monthly totals</h2>\n<table>\n<tr><th>Location</th><th>Month</th><th>Pedestrians</th><th>Bicycles</th><th>Total</th><th>Same days last year</th><th>Change</th><th>Daily totals</th></tr>"
+    );
+    for (location_id, location) in &report.locations {
+        let name = match names.get(location_id) {
+            Some(name) => format!("{} ({location_id})", escape(name)),
+            None => location_id.to_string(),
+        };
+        let has_issue = report
+            .warnings
+            .iter()
+            .any(|w| w.location_id == Some(*location_id));
+        let class = if has_issue { r#" class="issue""# } else { "" };
+        for month in &location.monthly_totals {
+            let change = match (month.total, month.last_year_total) {
+                (Some(this), Some(last)) if last > 0 => {
+                    let pct = (this - last) as f64 / last as f64 * 100.0;
+                    let class = if pct >= 0.0 { "up" } else { "down" };
+                    format!(r#"<span class="{class}">{pct:+.1}%</span>"#)
+                }
+                _ => String::new(),
+            };
+            let days = location
+                .daily_totals
+                .iter()
+                .filter(|d| (d.date.year(), d.date.month()) == (month.year, month.month))
+                .cloned()
+                .collect::<Vec<_>>();
+            // Only the days imported are compared to last year, so say which they are if they're
+            // not the whole month.
+            let span = match (days.first(), days.last()) {
+                (Some(first), Some(last))
+                    if first.date.day() > 1
+                        || last.date.succ_opt().map(|d| d.month()) == Some(month.month) =>
+                {
+                    format!(" ({}–{})", first.date.day(), last.date.day())
+                }
+                _ => String::new(),
+            };
+            let _ = writeln!(
+                html,
+                "<tr{class}><td>{name}</td><td>{}-{:02}{span}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{change}</td><td>{}</td></tr>",
+                month.year,
+                month.month,
+                number(month.ped),
+                number(month.bike),
+                number(month.total),
+                number(month.last_year_total),
+                sparkline(&days)
+            );
+        }
+    }
+    let _ = writeln!(html, "</table>");
+
+    // Validation issues.
+    let _ = writeln!(html, "<h2>Validation issues</h2>");
+    if report.warnings.is_empty() {
+        let _ = writeln!(html, "<p>None.</p>");
+    } else {
+        let _ = writeln!(html, "<ul>");
+        for warning in &report.warnings {
+            let _ = writeln!(
+                html,
+                r#"<li class="issue"><strong>{}</strong>: {}</li>"#,
+                warning.kind,
+                escape(&warning.message)
+            );
+        }
+        let _ = writeln!(html, "</ul>");
+    }
+
+    let _ = writeln!(html, "</body>\n</html>");
+    html
+}
+
+// An inline SVG line of daily totals, with gaps for missing days.
+fn sparkline(days: &[DailyTotal]) -> String {
+    let max = days
+        .iter()
+        .filter_map(|d| d.total)
+        .max()
+        .unwrap_or(0)
+        .max(1) as f64;
+    let step = if days.len() > 1 {
+        SPARKLINE_WIDTH / (days.len() - 1) as f64
+    } else {
+        0.0
+    };
+
+    let mut lines = vec![];
+    let mut points = vec![];
+    for (i, day) in days.iter().enumerate() {
+        match day.total {
+            Some(total) => points.push(format!(
+                "{:.1},{:.1}",
+                i as f64 * step,
+                SPARKLINE_HEIGHT - total as f64 / max * SPARKLINE_HEIGHT
+            )),
+            None if !points.is_empty() => lines.push(std::mem::take(&mut points)),
+            None => (),
+        }
+    }
+    if !points.is_empty() {
+        lines.push(points);
+    }
+
+    let mut svg = format!(
+        r#"<svg width="{SPARKLINE_WIDTH}" height="{SPARKLINE_HEIGHT}" viewBox="0 0 {SPARKLINE_WIDTH} {SPARKLINE_HEIGHT}">"#
+    );
+    for points in lines {
+        let _ = write!(
+            svg,
+            r##"<polyline fill="none" stroke="#36c" stroke-width="1" points="{}"/>"##,
+            points.join(" ")
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn number(v: Option<i32>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,60 @@
+// Importing bicycle and pedestrian counts exported from Eco-Counter: parsing the export
+// (`parser`), summing it by day (`aggregation`), and storing both (`store`), with `pipeline`
+// running an import from start to finish. The eco-counter-import binary watches for exports and
+// imports each with the Oracle store.
+//
+// Only the parser and aggregation are always built. The import needs the `pipeline` feature, and
+// the rest of what the binary uses the `service` feature (the default).
+pub mod aggregation;
+pub mod error;
+pub mod health;
+pub mod parser;
+pub mod peak;
+
+#[cfg(feature = "pipeline")]
+pub mod annotation;
+#[cfg(feature = "pipeline")]
+pub mod anomaly;
+#[cfg(feature = "pipeline")]
+pub mod credentials;
+#[cfg(feature = "pipeline")]
+pub mod db;
+#[cfg(feature = "pipeline")]
+pub mod export;
+#[cfg(feature = "pipeline")]
+pub mod factors;
+#[cfg(feature = "pipeline")]
+pub mod geojson;
+#[cfg(feature = "pipeline")]
+pub mod html;
+#[cfg(feature = "pipeline")]
+pub mod pipeline;
+#[cfg(feature = "pipeline")]
+pub mod report;
+#[cfg(feature = "pipeline")]
+pub mod store;
+
+#[cfg(feature = "service")]
+pub mod config;
+#[cfg(feature = "service")]
+pub mod logging;
+#[cfg(feature = "service")]
+pub mod metrics;
+#[cfg(feature = "service")]
+pub mod notify;
+#[cfg(feature = "service")]
+pub mod server;
+#[cfg(feature = "service")]
+pub mod shutdown;
+#[cfg(feature = "service")]
+pub mod status;
+#[cfg(feature = "service")]
+pub mod systemd;
+#[cfg(feature = "service")]
+pub mod webhook;
+
+pub use aggregation::{aggregate, AggregatedCount};
+pub use error::Error;
+pub use parser::{parse, IndividualCount};
+#[cfg(feature = "pipeline")]
+pub use store::{OracleStore, Store};
@@ -0,0 +1,309 @@
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use file_rotate::suffix::{AppendTimestamp, FileLimit};
+use file_rotate::{compression::Compression, ContentLimit, FileRotate, TimeFrequency};
+use log::kv::{self, Key, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Map, Value};
+use simplelog::*;
+use systemd_journal_logger::JournalLog;
+
+pub const DEFAULT_MAX_SIZE_MB: usize = 10;
+pub const DEFAULT_KEEP: usize = 30;
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+pub const DEFAULT_TERMINAL_LEVEL: LevelFilter = LevelFilter::Debug;
+
+// The import in progress, added to every line of JSON logs.
+struct Context {
+    run_id: Option<String>,
+    file: Option<String>,
+    phase: Option<&'static str>,
+}
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    run_id: None,
+    file: None,
+    phase: None,
+});
+
+pub fn start_run(run_id: String, file: &str) {
+    let mut context = CONTEXT.lock().unwrap();
+    context.run_id = Some(run_id);
+    context.file = Some(file.to_string());
+    context.phase = None;
+}
+
+pub fn set_phase(phase: Option<&'static str>) {
+    CONTEXT.lock().unwrap().phase = phase;
+}
+
+pub fn end_run() {
+    let mut context = CONTEXT.lock().unwrap();
+    context.run_id = None;
+    context.file = None;
+    context.phase = None;
+}
+
+// The context as (field, value)s, for those that are set.
+fn context_fields() -> Vec<(&'static str, String)> {
+    let context = CONTEXT.lock().unwrap();
+    let mut fields = vec![];
+    if let Some(run_id) = &context.run_id {
+        fields.push(("run_id", run_id.clone()));
+    }
+    if let Some(file) = &context.file {
+        fields.push(("file", file.clone()));
+    }
+    if let Some(phase) = context.phase {
+        fields.push(("phase", phase.to_string()));
+    }
+    fields
+}
+
+// How to log, configured by:
+//   - LOG_TARGET: "file" (default) or "journald", to log only to the systemd journal instead
+//   - LOG_FORMAT: "text" (default) or "json", for the log file
+//   - LOG_ROTATE: "size" or "daily" to rotate the log file; by default it isn't rotated
+//   - LOG_MAX_SIZE_MB: the size to rotate at, with LOG_ROTATE=size (default 10)
+//   - LOG_KEEP: the number of rotated files to keep (default 30)
+//   - LOG_LEVEL: the level to log to the file or journal at (default info)
+//   - LOG_TERMINAL_LEVEL: the level to log to the terminal at (default debug)
+#[derive(Debug)]
+pub struct LogConfig {
+    journald: bool,
+    json: bool,
+    rotate: Option<ContentLimit>,
+    keep: usize,
+    level: LevelFilter,
+    terminal_level: LevelFilter,
+}
+
+impl LogConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let number = |var: &str, default: usize| match env::var(var) {
+            Ok(v) => match v.parse::<usize>() {
+                Ok(v) if v > 0 => Ok(v),
+                _ => Err(format!("{var} must be a positive number, got {v}.")),
+            },
+            Err(_) => Ok(default),
+        };
+        let level = |var: &str, default: LevelFilter| match env::var(var) {
+            Ok(v) => v.parse().map_err(|_| {
+                format!("{var} must be off, error, warn, info, debug, or trace, got {v}.")
+            }),
+            Err(_) => Ok(default),
+        };
+        Ok(Self {
+            journald: match env::var("LOG_TARGET").as_deref() {
+                Ok("journald") => true,
+                Ok("file") | Err(_) => false,
+                Ok(v) => return Err(format!("LOG_TARGET must be file or journald, got {v}.")),
+            },
+            json: match env::var("LOG_FORMAT").as_deref() {
+                Ok("json") => true,
+                Ok("text") | Err(_) => false,
+                Ok(v) => return Err(format!("LOG_FORMAT must be text or json, got {v}.")),
+            },
+            rotate: match env::var("LOG_ROTATE").as_deref() {
+                Ok("size") => Some(ContentLimit::BytesSurpassed(
+                    number("LOG_MAX_SIZE_MB", DEFAULT_MAX_SIZE_MB)? * 1024 * 1024,
+                )),
+                Ok("daily") => Some(ContentLimit::Time(TimeFrequency::Daily)),
+                Ok(v) => return Err(format!("LOG_ROTATE must be size or daily, got {v}.")),
+                Err(_) => None,
+            },
+            keep: number("LOG_KEEP", DEFAULT_KEEP)?,
+            level: level("LOG_LEVEL", DEFAULT_LEVEL)?,
+            terminal_level: level("LOG_TERMINAL_LEVEL", DEFAULT_TERMINAL_LEVEL)?,
+        })
+    }
+}
+
+// Set up logging to the terminal and to `{storage_path}/log.txt`, or to the journal.
+pub fn init(storage_path: &str, config: LogConfig) -> Result<(), String> {
+    if config.journald {
+        let journal =
+            JournalLog::new().map_err(|e| format!("Could not connect to journald: {e}"))?;
+        return CombinedLogger::init(vec![Box::new(JournaldLogger {
+            level: config.level,
+            journal,
+        })])
+        .map_err(|e| e.to_string());
+    }
+
+    let path = format!("{storage_path}/log.txt");
+    let file: Box<dyn Write + Send> = match config.rotate {
+        Some(limit) => Box::new(FileRotate::new(
+            &path,
+            AppendTimestamp::default(FileLimit::MaxFiles(config.keep)),
+            limit,
+            Compression::None,
+            None,
+        )),
+        None => Box::new(
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .map_err(|e| format!("Could not open log file: {e}"))?,
+        ),
+    };
+
+    let simplelog_config = ConfigBuilder::new().set_time_format_rfc3339().build();
+    let file_logger: Box<dyn SharedLogger> = if config.json {
+        Box::new(JsonLogger {
+            level: config.level,
+            writer: Mutex::new(file),
+        })
+    } else {
+        WriteLogger::new(config.level, simplelog_config.clone(), file)
+    };
+    CombinedLogger::init(vec![
+        TermLogger::new(
+            config.terminal_level,
+            simplelog_config,
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        ),
+        file_logger,
+    ])
+    .map_err(|e| e.to_string())
+}
+
+// Writes each record as a line of JSON, with the context of the import in progress and any
+// key-values of the record (e.g. `warn!(location_id = 5; "...")`).
+struct JsonLogger {
+    level: LevelFilter,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = Map::new();
+        line.insert("time".into(), json!(Local::now().to_rfc3339()));
+        line.insert("level".into(), json!(record.level().as_str()));
+        line.insert("target".into(), json!(record.target()));
+        line.insert("message".into(), json!(record.args().to_string()));
+        for (field, value) in context_fields() {
+            line.insert(field.into(), json!(value));
+        }
+        let _ = record.key_values().visit(&mut JsonFields(&mut line));
+
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{}", Value::Object(line));
+        let _ = writer.flush();
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().unwrap().flush();
+    }
+}
+
+impl SharedLogger for JsonLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(v) = value.to_i64() {
+            json!(v)
+        } else if let Some(v) = value.to_u64() {
+            json!(v)
+        } else if let Some(v) = value.to_f64() {
+            json!(v)
+        } else if let Some(v) = value.to_bool() {
+            json!(v)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+// Sends each record to the systemd journal, with the context of the import in progress and any
+// key-values of the record as fields (e.g. RUN_ID, PHASE, LOCATION_ID).
+struct JournaldLogger {
+    level: LevelFilter,
+    journal: JournalLog,
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = context_fields()
+            .into_iter()
+            .map(|(field, value)| (field.to_string(), value))
+            .collect::<Vec<_>>();
+        let _ = record.key_values().visit(&mut TextFields(&mut fields));
+        let fields = fields
+            .iter()
+            .map(|(field, value)| (field.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        let fields = fields.as_slice();
+        self.journal.log(
+            &Record::builder()
+                .args(*record.args())
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .key_values(&fields)
+                .build(),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for JournaldLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+struct TextFields<'a>(&'a mut Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
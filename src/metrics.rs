@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use oracle::sql_type::Timestamp;
+use oracle::{Connection, Error as OracleError};
+
+use crate::report::{RunReport, Status};
+
+// Counters and gauges about imports, exposed in the Prometheus text format at /metrics.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pool_errors: AtomicU64,
+    imports: Mutex<ImportMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct ImportMetrics {
+    attempted: u64,
+    succeeded: u64,
+    failed: u64,
+    rows_deleted: BTreeMap<&'static str, u64>,
+    rows_inserted: BTreeMap<&'static str, u64>,
+    last_success: Option<DateTime<Local>>,
+    // The most recent date with data for each location, from the database at startup and
+    // successful imports since.
+    last_data_date: BTreeMap<i32, NaiveDate>,
+    // Durations of the phases of the most recent import.
+    phase_seconds: Vec<(&'static str, f64)>,
+}
+
+impl Metrics {
+    // Count a failure to create the connection pool or get a connection from it.
+    pub fn pool_error(&self) {
+        self.pool_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Start the most recent date with data for each location from what's in TBLHEADER, so the
+    // gauge doesn't start empty (and alerts on it fire) each time the program restarts.
+    pub fn load_last_data_dates(&self, conn: &Connection) -> Result<(), OracleError> {
+        let rows = conn.query_as::<(i32, Timestamp)>(
+            "select locationid, max(countdate) from TBLHEADER where total is not null group by locationid",
+            &[],
+        )?;
+        let mut m = self.imports.lock().unwrap();
+        for row in rows {
+            let (location_id, countdate) = row?;
+            if let Some(date) =
+                NaiveDate::from_ymd_opt(countdate.year(), countdate.month(), countdate.day())
+            {
+                let last = m.last_data_date.entry(location_id).or_insert(date);
+                *last = date.max(*last);
+            }
+        }
+        Ok(())
+    }
+
+    // Record a finished import.
+    pub fn record(&self, report: &RunReport) {
+        let mut m = self.imports.lock().unwrap();
+        m.attempted += 1;
+        match report.status {
+            Status::Failed => m.failed += 1,
+            // Neither, as it will be imported again.
+            Status::Stopped => (),
+            _ => {
+                m.succeeded += 1;
+                m.last_success = report.finished;
+                for (location_id, location) in &report.locations {
+                    if let Some(date) = location
+                        .daily_totals
+                        .iter()
+                        .filter(|d| d.total.is_some())
+                        .map(|d| d.date)
+                        .max()
+                    {
+                        let last = m.last_data_date.entry(*location_id).or_insert(date);
+                        *last = date.max(*last);
+                    }
+                }
+            }
+        }
+        for (table, counts) in &report.tables {
+            *m.rows_deleted.entry(table).or_default() += counts.deleted;
+            *m.rows_inserted.entry(table).or_default() += counts.inserted;
+        }
+        m.phase_seconds = report.phases.iter().map(|p| (p.name, p.seconds)).collect();
+    }
+
+    pub fn render(&self) -> String {
+        let m = self.imports.lock().unwrap();
+        let mut out = String::new();
+
+        // Each metric's HELP and TYPE lines, followed by its samples as (labels, value).
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            // Writing to a String can't fail.
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let unlabelled = |v: u64| vec![(String::new(), v.to_string())];
+        let by_table = |tables: &BTreeMap<&str, u64>| {
+            tables
+                .iter()
+                .map(|(table, n)| (format!("{{table=\"{table}\"}}"), n.to_string()))
+                .collect()
+        };
+
+        metric(
+            "eco_counter_imports_attempted_total",
+            "counter",
+            "Imports attempted.",
+            unlabelled(m.attempted),
+        );
+        metric(
+            "eco_counter_imports_succeeded_total",
+            "counter",
+            "Imports that succeeded.",
+            unlabelled(m.succeeded),
+        );
+        metric(
+            "eco_counter_imports_failed_total",
+            "counter",
+            "Imports that failed.",
+            unlabelled(m.failed),
+        );
+        metric(
+            "eco_counter_rows_deleted_total",
+            "counter",
+            "Rows deleted, by table.",
+            by_table(&m.rows_deleted),
+        );
+        metric(
+            "eco_counter_rows_inserted_total",
+            "counter",
+            "Rows inserted, by table.",
+            by_table(&m.rows_inserted),
+        );
+        metric(
+            "eco_counter_last_success_timestamp_seconds",
+            "gauge",
+            "When the last successful import finished.",
+            m.last_success
+                .iter()
+                .map(|t| (String::new(), t.timestamp().to_string()))
+                .collect(),
+        );
+        metric(
+            "eco_counter_last_data_date_timestamp_seconds",
+            "gauge",
+            "The most recent date with data imported, by location (midnight UTC).",
+            m.last_data_date
+                .iter()
+                .map(|(location_id, date)| {
+                    (
+                        format!("{{location=\"{location_id}\"}}"),
+                        date.and_time(NaiveTime::MIN)
+                            .and_utc()
+                            .timestamp()
+                            .to_string(),
+                    )
+                })
+                .collect(),
+        );
+        metric(
+            "eco_counter_phase_duration_seconds",
+            "gauge",
+            "Duration of each phase of the most recent import.",
+            m.phase_seconds
+                .iter()
+                .map(|(phase, seconds)| (format!("{{phase=\"{phase}\"}}"), seconds.to_string()))
+                .collect(),
+        );
+        metric(
+            "eco_counter_pool_connection_errors_total",
+            "counter",
+            "Failures to create the connection pool or get a connection from it.",
+            unlabelled(self.pool_errors.load(Ordering::Relaxed)),
+        );
+        out
+    }
+}
@@ -0,0 +1,279 @@
+use std::env;
+use std::fmt::Write;
+use std::time::Duration;
+
+use lettre::message::{header::ContentType, Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+
+use crate::report::{RunReport, Status};
+
+pub const DEFAULT_TLS: &str = "starttls";
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Number of warnings to list in a success email; the rest are only counted.
+const MAX_WARNINGS: usize = 20;
+
+// Sends an email about each import: always on failure, and on success if configured to.
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    on_success: bool,
+}
+
+impl Mailer {
+    // Configure from the environment. Returns None if SMTP_SERVER isn't set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(server) = env::var("SMTP_SERVER") else {
+            return Ok(None);
+        };
+        let tls = env::var("SMTP_TLS").unwrap_or_else(|_| DEFAULT_TLS.to_string());
+        let mut builder = match tls.to_lowercase().as_str() {
+            "tls" => SmtpTransport::relay(&server),
+            "starttls" => SmtpTransport::starttls_relay(&server),
+            "none" => Ok(SmtpTransport::builder_dangerous(&server)),
+            _ => {
+                return Err(format!(
+                    "SMTP_TLS must be tls, starttls, or none, got {tls}."
+                ))
+            }
+        }
+        .map_err(|e| format!("Invalid SMTP_SERVER {server}: {e}"))?
+        .timeout(Some(SMTP_TIMEOUT));
+        if let Ok(port) = env::var("SMTP_PORT") {
+            builder = builder.port(
+                port.parse()
+                    .map_err(|_| format!("SMTP_PORT must be a port number, got {port}."))?,
+            );
+        }
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        let from = env::var("EMAIL_FROM")
+            .map_err(|e| format!("EMAIL_FROM is required to send email: {e}."))?;
+        let from = from
+            .parse()
+            .map_err(|e| format!("Invalid EMAIL_FROM {from}: {e}"))?;
+        let to = env::var("EMAIL_TO")
+            .map_err(|e| format!("EMAIL_TO is required to send email: {e}."))?
+            .split(',')
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| {
+                v.trim()
+                    .parse()
+                    .map_err(|e| format!("Invalid EMAIL_TO address {v}: {e}"))
+            })
+            .collect::<Result<Vec<Mailbox>, _>>()?;
+        if to.is_empty() {
+            return Err("EMAIL_TO must contain at least one address.".to_string());
+        }
+        let on_success = match env::var("EMAIL_ON_SUCCESS") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| format!("EMAIL_ON_SUCCESS must be true or false, got {v}."))?,
+            Err(_) => false,
+        };
+
+        Ok(Some(Self {
+            transport: builder.build(),
+            from,
+            to,
+            on_success,
+        }))
+    }
+
+    // Email the outcome of an import, unless it succeeded and success emails are off, or it was
+    // stopped by a shutdown (and will be imported again).
+    pub fn notify(&self, report: &RunReport) -> Result<(), String> {
+        let subject = match report.status {
+            Status::Failed => format!("Eco-Counter import failed: {}", report.input_file),
+            Status::Stopped => return Ok(()),
+            _ if !self.on_success => return Ok(()),
+            _ => match &report.date_range {
+                Some(range) => format!(
+                    "Eco-Counter import succeeded: {} to {}",
+                    range.start, range.end
+                ),
+                None => "Eco-Counter import succeeded".to_string(),
+            },
+        };
+
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder.body(summary(report)).map_err(|e| e.to_string())?;
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// A plain-text summary of the import.
+fn summary(report: &RunReport) -> String {
+    let mut body = String::new();
+
+    // Writing to a String can't fail, so the results of writeln!() are ignored throughout.
+    let _ = writeln!(body, "File: {}", report.input_file);
+    let _ = writeln!(
+        body,
+        "Started: {}",
+        report.started.format("%Y-%m-%d %H:%M:%S")
+    );
+    if let Some(finished) = report.finished {
+        let _ = writeln!(body, "Finished: {}", finished.format("%Y-%m-%d %H:%M:%S"));
+    }
+    if let Some(error) = &report.error {
+        let _ = writeln!(body, "\nError: {error}");
+    }
+    if let Some(range) = &report.date_range {
+        let _ = writeln!(body, "\nDates: {} to {}", range.start, range.end);
+    }
+    if !report.locations.is_empty() {
+        let _ = writeln!(body, "Locations: {}", report.locations.len());
+    }
+
+    if !report.tables.is_empty() {
+        let _ = writeln!(body, "\nRows deleted/inserted:");
+        for (table, counts) in &report.tables {
+            let _ = writeln!(body, "  {table}: {}/{}", counts.deleted, counts.inserted);
+        }
+    }
+
+    if !report.warnings.is_empty() {
+        let _ = writeln!(body, "\nWarnings ({}):", report.warnings.len());
+        for warning in report.warnings.iter().take(MAX_WARNINGS) {
+            let _ = writeln!(body, "  [{}] {}", warning.kind, warning.message);
+        }
+        if report.warnings.len() > MAX_WARNINGS {
+            let _ = writeln!(
+                body,
+                "  ... and {} more; see the run report.",
+                report.warnings.len() - MAX_WARNINGS
+            );
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write as _};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn mailer(port: u16, on_success: bool) -> Mailer {
+        Mailer {
+            transport: SmtpTransport::builder_dangerous("127.0.0.1")
+                .port(port)
+                .timeout(Some(Duration::from_secs(5)))
+                .build(),
+            from: "importer@example.com".parse().unwrap(),
+            to: vec![
+                "a@example.com".parse().unwrap(),
+                "b@example.com".parse().unwrap(),
+            ],
+            on_success,
+        }
+    }
+
+    // Accept one SMTP session on a local port, answering every command, and return the port
+    // and the envelope recipients and message it was sent.
+    fn smtp_sink() -> (u16, thread::JoinHandle<(Vec<String>, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut recipients = vec![];
+            let mut message = String::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_uppercase();
+                let reply: &[u8] = if command.starts_with("EHLO") {
+                    b"250 localhost\r\n"
+                } else if command.starts_with("RCPT TO:") {
+                    recipients.push(line.trim_end()[8..].to_string());
+                    b"250 OK\r\n"
+                } else if command == "DATA" {
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        message.push_str(&line);
+                    }
+                    b"250 Queued\r\n"
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).unwrap();
+                line.clear();
+            }
+            (recipients, message)
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn failed_import_is_emailed_to_everyone() {
+        let (port, sink) = smtp_sink();
+        let mut report = RunReport::new("export.csv");
+        report.warn(
+            "health",
+            Some(16),
+            "Counter 16 reported nothing".to_string(),
+        );
+        report.fail("Database error");
+
+        mailer(port, false).notify(&report).unwrap();
+        let (recipients, message) = sink.join().unwrap();
+        assert_eq!(recipients, ["<a@example.com>", "<b@example.com>"]);
+        assert!(message.contains("Subject: Eco-Counter import failed: export.csv"));
+        assert!(message.contains("Error: Database error"));
+        assert!(message.contains("[health] Counter 16 reported nothing"));
+    }
+
+    #[test]
+    fn success_is_only_emailed_if_configured() {
+        // Nothing listens on the port, so sending would fail.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut report = RunReport::new("export.csv");
+        report.succeed();
+        assert_eq!(mailer(port, false).notify(&report), Ok(()));
+        assert!(mailer(port, true).notify(&report).is_err());
+    }
+
+    #[test]
+    fn import_stopped_by_shutdown_is_not_emailed() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut report = RunReport::new("export.csv");
+        report.stop();
+        assert_eq!(mailer(port, true).notify(&report), Ok(()));
+    }
+}
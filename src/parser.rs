@@ -0,0 +1,640 @@
+use std::fmt;
+use std::io::Read;
+
+use chrono::prelude::*;
+use csv::StringRecord;
+use log::info;
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndividualCount {
+    pub location_id: i32,
+    pub datetime: NaiveDateTime,
+    pub total: Option<i32>,
+    pub ped_in: Option<i32>,
+    pub ped_out: Option<i32>,
+    pub bike_in: Option<i32>,
+    pub bike_out: Option<i32>,
+}
+
+impl IndividualCount {
+    pub fn new(
+        location_id: i32,
+        datetime: NaiveDateTime,
+        counts: &[Option<i32>],
+        ped: bool,
+        bike: bool,
+    ) -> Result<IndividualCount, CountError> {
+        let mut ped_in = None;
+        let mut ped_out = None;
+        let mut bike_in = None;
+        let mut bike_out = None;
+
+        // EcoVizio does not properly report the bike data for the two bike-lane-only counters:
+        // It produces 3 fields for them, but they are total, pedin, and pedout, rather than
+        // what they should be: total, bikein, bikeout. Fortunately, the total = bikein,
+        // so manually handle that. (bikeout is left as NULL, as these are one-way lanes.)
+        if ONE_WAY_BIKE_LANES.contains(&location_id) {
+            bike_in = counts[0];
+        } else {
+            // `counts` is a slice from the whole row, starting with total (index 0) and followed by
+            // either a ped or bike pair (in/out) or both (usually both)
+            if counts.len() == 5 {
+                if !bike && !ped {
+                    return Err(CountError::TooMany);
+                }
+                ped_in = counts[1];
+                ped_out = counts[2];
+                bike_in = counts[3];
+                bike_out = counts[4];
+            } else if counts.len() == 3 {
+                if bike && ped {
+                    return Err(CountError::TooFew);
+                }
+                if ped && !bike {
+                    ped_in = counts[1];
+                    ped_out = counts[2];
+                }
+                if !ped && bike {
+                    bike_in = counts[1];
+                    bike_out = counts[2];
+                }
+            } else {
+                return Err(CountError::UnexpectedNumber);
+            }
+        }
+
+        Ok(Self {
+            location_id,
+            datetime,
+            total: counts[0],
+            ped_in,
+            ped_out,
+            bike_in,
+            bike_out,
+        })
+    }
+}
+
+// This will catch any misconfiguration between the bools/counts provided in new().
+#[derive(Debug)]
+pub enum CountError {
+    TooFew,
+    TooMany,
+    UnexpectedNumber,
+}
+
+impl fmt::Display for CountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CountError::TooFew => {
+                write!(f, "Misconfiguration of count: expected more fields.")
+            }
+            CountError::TooMany => {
+                write!(f, "Misconfiguration of count: expected fewer fields.")
+            }
+            CountError::UnexpectedNumber => {
+                write!(f, "Expected 3 or 5 fields, got different amount.")
+            }
+        }
+    }
+}
+
+// The two bike-lane-only counters (Pine St and Spruce St), which count one-way traffic.
+pub const ONE_WAY_BIKE_LANES: &[i32] = &[24, 25];
+
+pub const EXPECTED_HEADER: &[&str] = &[
+    "Time",
+    "Bartram's Garden", // 16 (locationid)
+    "Bartram's Garden Pedestrians NB - Bartram's Garden",
+    "Bartram's Garden Pedestrians SB - Bartram's Garden",
+    "Bartram's Garden Cyclists NB - Bartram's Garden",
+    "Bartram's Garden Cyclists SB - Bartram's Garden",
+    "Chester Valley Trail - East Whiteland Twp", // 1
+    "Chester Valley Trail - East Whiteland Twp CVT - EB - Pedestrian",
+    "Chester Valley Trail - East Whiteland Twp CVT - WB - Pedestrian",
+    "Chester Valley Trail - East Whiteland Twp CVT - EB - Bicycle",
+    "Chester Valley Trail - East Whiteland Twp CVT - WB - Bicycle",
+    "Cooper River Trail", // 11
+    "Cooper River Trail - EB Pedestrian",
+    "Cooper River Trail - WB Pedestrian",
+    "Cooper River Trail - EB Bicycle",
+    "Cooper River Trail - WB Bicycle",
+    "Cynwyd Heritage Trail", // 3
+    "Cynwyd Heritage Trail Pedestrian IN",
+    "Cynwyd Heritage Trail Pedestrian OUT",
+    "Cynwyd Heritage Trail CHT - WB - Bicycle",
+    "Cynwyd Heritage Trail CHT - EB - Bicycle",
+    "Darby Creek Trail", // 12
+    "Darby Creek Trail - Pedestrians - SB",
+    "Darby Creek Trail - Pedestrians - NB",
+    "Darby Creek Trail - Bicycle - SB",
+    "Darby Creek Trail - Bicycle - NB",
+    "Kelly Dr - Schuylkill River Trail", // 5
+    "Kelly Dr - Schuylkill River Trail Kelly Drive - Pedestrians - NB",
+    "Kelly Dr - Schuylkill River Trail Kelly Drive - Pedestrians - SB",
+    "Kelly Dr - Schuylkill River Trail Kelly Drive - Bicycle - NB",
+    "Kelly Dr - Schuylkill River Trail Kelly Drive - Bicycle - SB",
+    "Lawrence - Hopewell Trail", // 8
+    "Lawrence - Hopewell Trail LHT - Pedestrian - NB",
+    "Lawrence - Hopewell Trail LHT - Pedestrian - SB",
+    "Lawrence - Hopewell Trail LHT - Bicycle - NB",
+    "Lawrence - Hopewell Trail LHT - Bicycle - SB",
+    "Monroe Twp", // 10
+    "Monroe Twp Pedestrian IN",
+    "Monroe Twp Pedestrian OUT",
+    "Monroe Twp Monroe - Bicycle - EB",
+    "Monroe Twp Monroe - Bicycle - WB",
+    "Pawlings Rd - Schuylkill River Trail", // 2
+    "Pawlings Rd - Schuylkill River Trail Pawlings Rd - WB Pedestrian",
+    "Pawlings Rd - Schuylkill River Trail Pawlings Rd - EB Pedestrian",
+    "Pawlings Rd - Schuylkill River Trail Pawlings Rd - WB - Bicycle",
+    "Pawlings Rd - Schuylkill River Trail Pawlings Rd - EB - Bicycle",
+    "Pine St",                // 24 "Pine St Bike Lanes"  - one-way, east-bound
+    "Pine St Pedestrian IN",  // misnamed and empty, but total is all we need
+    "Pine St Pedestrian OUT", // misnamed and empty, but total is all we need
+    "Port Richmond",          // 7
+    "Port Richmond - WB - Pedestrian",
+    "Port Richmond - EB - Pedestrian",
+    "Port Richmond - WB - Bicycle",
+    "Port Richmond - EB - Bicycle",
+    "Schuylkill Banks", // 6
+    "Schuylkill Banks - Pedestrian - NB",
+    "Schuylkill Banks - Pedestrian - SB",
+    "Schuylkill Banks - Bicycle - NB",
+    "Schuylkill Banks - Bicycle - SB",
+    "Spring Mill Station", // 13
+    "Spring Mill Station Pedestrians EB - To Philadelphia",
+    "Spring Mill Station Pedestrians WB - To Conshohocken",
+    "Spring Mill Station Cyclists EB - To Philadelphia",
+    "Spring Mill Station Cyclists WB - To Conshohocken",
+    "Spruce St",                // 25 "Spruce St Bike Lanes" - one-way, west-bound
+    "Spruce St Pedestrian IN",  // misnamed and empty, but total is all we need
+    "Spruce St Pedestrian OUT", // misnamed and empty, but total is all we need
+    "Tinicum Park - D&L Trail", // 23
+    "Tinicum Park - D&L Trail Hugh Moore Park - D&L Trail Pedestrians Wilkes-Barre (Bethlehem)",
+    "Tinicum Park - D&L Trail Pedestrians Bristol (New Hope)",
+    "Tinicum Park - D&L Trail Hugh Moore Park - D&L Trail Cyclists Wilkes-Barre (Bethlehem)",
+    "Tinicum Park - D&L Trail Cyclists Bristol (New Hope)",
+    "Tullytown", // 14
+    "Tullytown Pedestrians NB - Towards Trenton - IN",
+    "Tullytown Pedestrians SB - Towards Tullytown - OUT",
+    "Tullytown Cyclists NB - Towards Trenton - IN",
+    "Tullytown Cyclists SB - Towards Tullytown - OUT",
+    "US 202 Parkway Trail", // 9
+    "US 202 Parkway Trail US 202 Parkway - SB - Pedestrian",
+    "US 202 Parkway Trail US 202 Parkway - NB - Pedestrian",
+    "US 202 Parkway Trail US 202 Parkway - SB - Bicycle",
+    "US 202 Parkway Trail US 202 Parkway - NB - Bicycle",
+    "Washington Crossing", // 15
+    "Washington Crossing Pedestrians NB - To New Hope - IN",
+    "Washington Crossing Pedestrians SB - To Yardley - OUT",
+    "Washington Crossing Cyclists NB - To New Hope - IN",
+    "Washington Crossing Cyclists SB - To Yardley - OUT",
+    "Waterfront Display", // 26
+    "Waterfront Display Pedestrian IN",
+    "Waterfront Display Pedestrian OUT",
+    "Waterfront Display Cyclist IN",
+    "Waterfront Display Cyclist OUT",
+    "Wissahickon Trail", // 4
+    "Wissahickon Trail - Pedestrians - SB",
+    "Wissahickon Trail - Pedestrians - NB",
+    "Wissahickon Trail - Bicycles - SB",
+    "Wissahickon Trail - Bicycles - NB",
+    "",
+];
+
+// Parse a whole export from Eco-Counter into a count for each location and time. (`rows` reads
+// it a row at a time instead, for exports too large to hold in memory.)
+pub fn parse(reader: impl Read) -> Result<Vec<IndividualCount>, Error> {
+    info!("Extracting counts from CSV file.");
+    let mut all_counts = vec![];
+    for row in rows(reader)? {
+        all_counts.extend(row?);
+    }
+    Ok(all_counts)
+}
+
+// The rows of an export, each as a count for each location at the row's time.
+pub struct Rows<R> {
+    records: csv::StringRecordsIntoIter<R>,
+}
+
+// Start reading an export from Eco-Counter, checking that its header is the one expected.
+pub fn rows<R: Read>(reader: R) -> Result<Rows<R>, Error> {
+    // Create CSV reader over file, verify header is what we expect it to be.
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(reader);
+
+    let expected_header = StringRecord::from(EXPECTED_HEADER);
+    let header: StringRecord = match rdr.records().skip(1).take(1).next() {
+        Some(v) => match v {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(Error::Parse(format!("Could not parse header: {e}")));
+            }
+        },
+        None => {
+            return Err(Error::Parse("Header not found.".to_string()));
+        }
+    };
+
+    if header != expected_header {
+        return Err(Error::Parse(
+            "Header file does match expected header.".to_string(),
+        ));
+    }
+
+    Ok(Rows {
+        records: rdr.into_records(),
+    })
+}
+
+impl<R: Read> Iterator for Rows<R> {
+    type Item = Result<Vec<IndividualCount>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next().map(parse_row)
+    }
+}
+
+// Create a count for each location from a row of the export.
+fn parse_row(result: csv::Result<StringRecord>) -> Result<Vec<IndividualCount>, Error> {
+    let record = match result {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Parse(format!("Could not read row from CSV: {e}.")));
+        }
+    };
+
+    // Extract datetime.
+    let datetime = &record[0];
+    let datetime = match NaiveDateTime::parse_from_str(datetime, "%b %e, %Y %l:%M %p") {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Parse(format!(
+                "Could not parse date ({datetime}) from record: {e}."
+            )));
+        }
+    };
+
+    // Extract everything, by particular location/count, converting to Options from &str.
+    let counts = record
+        .iter()
+        .map(|v| v.parse::<i32>().ok())
+        .collect::<Vec<_>>();
+
+    // Creation of `IndividualCount`s could possibly result in out-of-bounds error, so
+    // check length first before trying to create them, in order to log error and continue
+    // running the program.
+    if counts.len() != EXPECTED_HEADER.len() {
+        return Err(Error::Validation(format!(
+            "Incorrect number of fields in row. Expected {}, found {}.",
+            EXPECTED_HEADER.len(),
+            counts.len()
+        )));
+    }
+    // Create counts.
+    let mut row_counts = vec![];
+    let current_location = "Bartram";
+    let count = match IndividualCount::new(16, datetime, &counts[1..=5], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Chester Valley Trail";
+    let count = match IndividualCount::new(1, datetime, &counts[6..=10], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Cooper River Trail";
+    let count = match IndividualCount::new(11, datetime, &counts[11..=15], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Cynwyd Heritage Trail";
+    let count = match IndividualCount::new(3, datetime, &counts[16..=20], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Darby Creek Trail";
+    let count = match IndividualCount::new(12, datetime, &counts[21..=25], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Kelly Dr";
+    let count = match IndividualCount::new(5, datetime, &counts[26..=30], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Lawrence Hopewell trail";
+    let count = match IndividualCount::new(8, datetime, &counts[31..=35], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Monroe Twp";
+    let count = match IndividualCount::new(10, datetime, &counts[36..=40], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Pawlings Rd";
+    let count = match IndividualCount::new(2, datetime, &counts[41..=45], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Pine Street";
+    let count = match IndividualCount::new(24, datetime, &counts[46..=48], false, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Port Richmond";
+    let count = match IndividualCount::new(7, datetime, &counts[49..=53], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Schuylkill Banks";
+    let count = match IndividualCount::new(6, datetime, &counts[54..=58], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Spring Mill Station";
+    let count = match IndividualCount::new(13, datetime, &counts[59..=63], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Spruce St";
+    let count = match IndividualCount::new(25, datetime, &counts[64..=66], false, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Tinicum Park";
+    let count = match IndividualCount::new(23, datetime, &counts[67..=71], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Tullytown";
+    let count = match IndividualCount::new(14, datetime, &counts[72..=76], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "US 202 Parkway Trail";
+    let count = match IndividualCount::new(9, datetime, &counts[77..=81], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Washington Cross";
+    let count = match IndividualCount::new(15, datetime, &counts[82..=86], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Waterfront Display";
+    let count = match IndividualCount::new(26, datetime, &counts[87..=91], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+    let current_location = "Wissahickon Trail";
+    let count = match IndividualCount::new(4, datetime, &counts[92..=96], true, true) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(Error::Validation(format!(
+                "Error creating count for {}: {}",
+                current_location, e
+            )));
+        }
+    };
+    row_counts.push(count);
+
+    Ok(row_counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime() -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(8, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn one_way_bike_lanes_count_total_as_bike_in() {
+        for location_id in ONE_WAY_BIKE_LANES {
+            let count =
+                IndividualCount::new(*location_id, datetime(), &[Some(7), None, None], true, true)
+                    .unwrap();
+            assert_eq!(count.total, Some(7));
+            assert_eq!(count.bike_in, Some(7));
+            assert_eq!(count.bike_out, None);
+            assert_eq!(count.ped_in, None);
+            assert_eq!(count.ped_out, None);
+        }
+    }
+
+    #[test]
+    fn one_way_bike_lanes_ignore_misnamed_pedestrian_columns() {
+        let count =
+            IndividualCount::new(24, datetime(), &[Some(7), Some(1), Some(2)], true, true).unwrap();
+        assert_eq!(count.bike_in, Some(7));
+        assert_eq!(count.ped_in, None);
+        assert_eq!(count.ped_out, None);
+    }
+
+    #[test]
+    fn five_fields_are_ped_then_bike() {
+        let counts = [Some(10), Some(1), Some(2), Some(3), Some(4)];
+        let count = IndividualCount::new(16, datetime(), &counts, true, true).unwrap();
+        assert_eq!(
+            (
+                count.total,
+                count.ped_in,
+                count.ped_out,
+                count.bike_in,
+                count.bike_out
+            ),
+            (Some(10), Some(1), Some(2), Some(3), Some(4))
+        );
+    }
+
+    #[test]
+    fn three_fields_are_ped_or_bike() {
+        let counts = [Some(3), Some(1), Some(2)];
+        let ped = IndividualCount::new(16, datetime(), &counts, true, false).unwrap();
+        assert_eq!((ped.ped_in, ped.ped_out), (Some(1), Some(2)));
+        assert_eq!((ped.bike_in, ped.bike_out), (None, None));
+        let bike = IndividualCount::new(16, datetime(), &counts, false, true).unwrap();
+        assert_eq!((bike.bike_in, bike.bike_out), (Some(1), Some(2)));
+        assert_eq!((bike.ped_in, bike.ped_out), (None, None));
+    }
+
+    #[test]
+    fn misconfigured_counts_are_errors() {
+        let five = [Some(10), Some(1), Some(2), Some(3), Some(4)];
+        assert!(matches!(
+            IndividualCount::new(16, datetime(), &five, false, false),
+            Err(CountError::TooMany)
+        ));
+        let three = [Some(3), Some(1), Some(2)];
+        assert!(matches!(
+            IndividualCount::new(16, datetime(), &three, true, true),
+            Err(CountError::TooFew)
+        ));
+        for counts in [&five[..4], &five[..1]] {
+            assert!(matches!(
+                IndividualCount::new(16, datetime(), counts, true, true),
+                Err(CountError::UnexpectedNumber)
+            ));
+        }
+    }
+
+    #[test]
+    fn missing_values_are_none() {
+        let counts = [None, Some(1), None, None, Some(4)];
+        let count = IndividualCount::new(16, datetime(), &counts, true, true).unwrap();
+        assert_eq!(
+            (
+                count.total,
+                count.ped_in,
+                count.ped_out,
+                count.bike_in,
+                count.bike_out
+            ),
+            (None, Some(1), None, None, Some(4))
+        );
+    }
+
+    #[test]
+    fn header_must_match() {
+        let header = EXPECTED_HEADER
+            .iter()
+            .map(|h| format!("\"{h}\""))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert!(parse(format!("title\n{header}\n").as_bytes())
+            .unwrap()
+            .is_empty());
+        assert!(matches!(parse("title\n".as_bytes()), Err(Error::Parse(_))));
+        let renamed = header.replace("Pine St\"", "Pine Street\"");
+        assert!(matches!(
+            parse(format!("title\n{renamed}\n").as_bytes()),
+            Err(Error::Parse(_))
+        ));
+    }
+}
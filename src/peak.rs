@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::parser::IndividualCount;
+
+// The busiest 60-minute window in either the morning or the afternoon/evening of a day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakHour {
+    pub start: NaiveDateTime,
+    pub total: Option<i32>,
+    pub ped: Option<i32>,
+    pub bike: Option<i32>,
+    // Peak-hour factor: peak-hour volume divided by (bins per hour * highest bin in the window).
+    // Only meaningful when the data is at a finer resolution than an hour.
+    pub phf: Option<f64>,
+}
+
+// Find the AM and PM peak hour for every location and date in `counts`.
+//
+// Windows are 60 minutes long and are rolled forward one bin at a time, so with 15-minute data
+// a peak can start on the quarter hour. A window is AM if it starts before noon, PM otherwise,
+// and must end by midnight. Only windows of a full hour of data are considered.
+pub fn peak_hours(
+    counts: &[IndividualCount],
+) -> HashMap<(i32, NaiveDate), (Option<PeakHour>, Option<PeakHour>)> {
+    let mut by_day: HashMap<(i32, NaiveDate), Vec<&IndividualCount>> = HashMap::new();
+    for count in counts {
+        by_day
+            .entry((count.location_id, count.datetime.date()))
+            .or_default()
+            .push(count);
+    }
+
+    let mut peaks = HashMap::new();
+    for (key, mut bins) in by_day {
+        bins.sort_by_key(|c| c.datetime);
+        peaks.insert(key, day_peaks(key.1, &bins));
+    }
+    peaks
+}
+
+fn day_peaks(date: NaiveDate, bins: &[&IndividualCount]) -> (Option<PeakHour>, Option<PeakHour>) {
+    let interval = resolution(bins);
+    let bins_per_hour =
+        (Duration::hours(1).num_minutes() / interval.num_minutes().max(1)).max(1) as usize;
+
+    let noon = date.and_hms_opt(12, 0, 0).unwrap();
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap() + Duration::days(1);
+
+    let mut am: Option<PeakHour> = None;
+    let mut pm: Option<PeakHour> = None;
+
+    for (i, first) in bins.iter().enumerate() {
+        let start = first.datetime;
+        if start + Duration::hours(1) > midnight {
+            break;
+        }
+        // A window is an hour of consecutive bins, each with a total. One with a gap, cut off at
+        // the edge of the data, or with a bin repeated (as the hour is when clocks go back) isn't
+        // a full hour, so can't be a peak.
+        let Some(window) = bins.get(i..i + bins_per_hour) else {
+            break;
+        };
+        if window.iter().any(|c| c.total.is_none())
+            || window
+                .windows(2)
+                .any(|w| w[1].datetime - w[0].datetime != interval)
+        {
+            continue;
+        }
+
+        let total = sum(window.iter().map(|c| c.total));
+        let Some(volume) = total else {
+            continue;
+        };
+        let ped = sum(window.iter().flat_map(|c| [c.ped_in, c.ped_out]));
+        let bike = sum(window.iter().flat_map(|c| [c.bike_in, c.bike_out]));
+
+        let phf = match window.iter().filter_map(|c| c.total).max() {
+            Some(highest) if bins_per_hour > 1 && highest > 0 => {
+                Some(volume as f64 / (bins_per_hour as i32 * highest) as f64)
+            }
+            _ => None,
+        };
+
+        let candidate = PeakHour {
+            start,
+            total,
+            ped,
+            bike,
+            phf,
+        };
+        let current = if start < noon { &mut am } else { &mut pm };
+        match current {
+            Some(peak) if peak.total >= candidate.total => (),
+            _ => *current = Some(candidate),
+        }
+    }
+
+    (am, pm)
+}
+
+// The resolution of a series of counts (sorted by datetime) is the smallest gap between
+// consecutive bins; a lone bin is assumed to be hourly.
+pub fn resolution(bins: &[&IndividualCount]) -> Duration {
+    bins.windows(2)
+        .map(|w| w[1].datetime - w[0].datetime)
+        .filter(|d| *d > Duration::zero())
+        .min()
+        .unwrap_or(Duration::hours(1))
+}
+
+// Sum the values that are present, or None if none are.
+fn sum(values: impl Iterator<Item = Option<i32>>) -> Option<i32> {
+    values
+        .flatten()
+        .fold(None, |acc, v| Some(acc.unwrap_or(0) + v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 6, 1).unwrap()
+    }
+
+    fn count(hour: u32, minute: u32, total: Option<i32>) -> IndividualCount {
+        IndividualCount {
+            location_id: 16,
+            datetime: day().and_hms_opt(hour, minute, 0).unwrap(),
+            total,
+            ped_in: total,
+            ped_out: None,
+            bike_in: None,
+            bike_out: None,
+        }
+    }
+
+    fn quarter_hours(
+        from: u32,
+        to: u32,
+        total: impl Fn(u32, u32) -> Option<i32>,
+    ) -> Vec<IndividualCount> {
+        (from..to)
+            .flat_map(|hour| [0, 15, 30, 45].map(|minute| count(hour, minute, total(hour, minute))))
+            .collect()
+    }
+
+    fn peaks(counts: &[IndividualCount]) -> (Option<PeakHour>, Option<PeakHour>) {
+        peak_hours(counts).remove(&(16, day())).unwrap()
+    }
+
+    #[test]
+    fn finds_the_busiest_full_hour_with_its_factor() {
+        let counts = quarter_hours(0, 24, |hour, minute| match (hour, minute) {
+            (8, _) => Some(10),
+            (9, 0) => Some(30),
+            (17, _) => Some(20),
+            _ => Some(1),
+        });
+        let (am, pm) = peaks(&counts);
+        let am = am.unwrap();
+        assert_eq!(am.start, day().and_hms_opt(8, 15, 0).unwrap());
+        assert_eq!(am.total, Some(60));
+        assert_eq!(am.phf, Some(60.0 / (4.0 * 30.0)));
+        let pm = pm.unwrap();
+        assert_eq!(pm.start, day().and_hms_opt(17, 0, 0).unwrap());
+        assert_eq!(pm.total, Some(80));
+    }
+
+    #[test]
+    fn windows_with_a_gap_or_missing_total_are_not_peaks() {
+        // The busiest bins are either side of a missing bin (at 8:30) or a missing total (at
+        // 17:45), so no full hour includes both.
+        let mut counts = quarter_hours(0, 24, |hour, minute| match (hour, minute) {
+            (8, 15) | (8, 45) | (17, 30) | (18, 0) => Some(100),
+            (17, 45) => None,
+            _ => Some(1),
+        });
+        counts.retain(|c| c.datetime != day().and_hms_opt(8, 30, 0).unwrap());
+        let (am, pm) = peaks(&counts);
+        assert!(am.unwrap().total < Some(200));
+        assert!(pm.unwrap().total < Some(200));
+    }
+
+    #[test]
+    fn windows_cut_off_at_the_edge_of_the_data_are_not_peaks() {
+        // The data starts at 6:30 and ends at 18:15, with the busiest bins at either end.
+        let mut counts = quarter_hours(6, 19, |hour, _| match hour {
+            6 | 18 => Some(100),
+            _ => Some(1),
+        });
+        counts.retain(|c| {
+            c.datetime >= day().and_hms_opt(6, 30, 0).unwrap()
+                && c.datetime <= day().and_hms_opt(18, 15, 0).unwrap()
+        });
+        let (am, pm) = peaks(&counts);
+        assert_eq!(am.unwrap().total, Some(202));
+        assert_eq!(pm.unwrap().total, Some(202));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::prelude::*;
+use chrono::Duration;
+use log::{debug, error, info, warn};
+
+use crate::aggregation::{self, AggregatedCount};
+use crate::annotation::{self, Stations};
+use crate::anomaly::{self, Tally};
+use crate::error::Error;
+use crate::export;
+use crate::factors::FactorGroup;
+use crate::geojson::Location;
+use crate::health;
+use crate::parser::{self, IndividualCount};
+use crate::report::RunReport;
+use crate::store::{Day, Progress, Store};
+
+// Days of counts to import at a time, by default.
+pub const DEFAULT_CHUNK_DAYS: u32 = 7;
+
+// Settings used by each import.
+pub struct Settings {
+    pub anomaly_threshold: f64,
+    pub weather_path: Option<String>,
+    pub holidays_path: Option<String>,
+    pub factor_groups: Vec<FactorGroup>,
+    pub geojson_path: Option<String>,
+    pub locations: Vec<Location>,
+    pub export_dir: Option<String>,
+    // Days of counts to import at a time.
+    pub chunk_days: u32,
+}
+
+// Import the counts in `data_file` into `store`.
+//
+// The file is read twice: first to check that all of it can be parsed, so that a malformed file
+// doesn't change anything, and then to import it in chunks of up to `settings.chunk_days` days
+// (never spanning two months). Each chunk is checked and replaces the counts for its dates before
+// the next is read, so only one chunk is held in memory however large the file is.
+pub fn import(
+    mut data_file: impl Read + Seek,
+    store: &dyn Store,
+    settings: &Settings,
+    report: &mut RunReport,
+    shutdown: &AtomicBool,
+) -> Result<(), Error> {
+    report.phase("parse");
+    info!("Checking CSV file.");
+    let size = scan(&mut data_file)?;
+    data_file
+        .rewind()
+        .map_err(|e| Error::Parse(format!("Could not read CSV file again: {e}")))?;
+
+    let mut loader = Loader::new(store, settings, report, shutdown, &size);
+    info!("Importing counts from CSV file.");
+    let mut chunk: Vec<IndividualCount> = vec![];
+    for row in parser::rows(data_file)? {
+        let counts = row?;
+        if let (Some(start), Some(count)) = (chunk.first(), counts.first()) {
+            if !same_chunk(
+                start.datetime.date(),
+                count.datetime.date(),
+                settings.chunk_days,
+            ) {
+                loader.load(std::mem::take(&mut chunk))?;
+            }
+        }
+        chunk.extend(counts);
+    }
+    if !chunk.is_empty() {
+        loader.load(chunk)?;
+    }
+    loader.finish();
+    Ok(())
+}
+
+// How much is in an export, for reporting progress.
+#[derive(Debug, Default)]
+struct Size {
+    dates: usize,
+    counts: usize,
+    daily_counts: usize,
+}
+
+// Read the whole export without keeping it, checking that every row can be parsed and that the
+// rows are in date order (which importing in chunks relies on).
+fn scan(data_file: impl Read) -> Result<Size, Error> {
+    let mut size = Size::default();
+    let mut last_date = None;
+    for row in parser::rows(data_file)? {
+        let counts = row?;
+        let Some(date) = counts.first().map(|c| c.datetime.date()) else {
+            continue;
+        };
+        match last_date {
+            Some(last) if date < last => {
+                return Err(Error::Validation(format!(
+                    "Rows are out of order: {date} comes after {last}."
+                )));
+            }
+            Some(last) if date == last => (),
+            _ => {
+                size.dates += 1;
+                size.daily_counts += counts.len();
+            }
+        }
+        last_date = Some(date);
+        size.counts += counts.len();
+    }
+    Ok(size)
+}
+
+// Split a chunk's counts by date.
+fn days(counts: Vec<IndividualCount>, daily_counts: Vec<AggregatedCount>) -> Vec<Day> {
+    let mut days: BTreeMap<NaiveDate, Day> = BTreeMap::new();
+    let new = |date| Day {
+        date,
+        counts: vec![],
+        daily_counts: vec![],
+    };
+    for count in counts {
+        let date = count.datetime.date();
+        days.entry(date)
+            .or_insert_with(|| new(date))
+            .counts
+            .push(count);
+    }
+    for count in daily_counts {
+        let date = count.date;
+        days.entry(date)
+            .or_insert_with(|| new(date))
+            .daily_counts
+            .push(count);
+    }
+    days.into_values().collect()
+}
+
+// Whether counts on `date` belong in the chunk starting on `start`.
+fn same_chunk(start: NaiveDate, date: NaiveDate, days: u32) -> bool {
+    date < start + Duration::days(days.into()) && month(date) == month(start)
+}
+
+// Imports chunks of an export, keeping what's needed from one chunk to the next.
+struct Loader<'a> {
+    store: &'a dyn Store,
+    settings: &'a Settings,
+    report: &'a mut RunReport,
+    shutdown: &'a AtomicBool,
+    // Weather and holidays to annotate daily counts with, if either file is configured.
+    annotations: Option<(Stations, HashMap<NaiveDate, String>)>,
+    tally: Tally,
+    health: health::Monitor,
+    latest: Option<NaiveDate>,
+    progress: Progress,
+}
+
+impl<'a> Loader<'a> {
+    fn new(
+        store: &'a dyn Store,
+        settings: &'a Settings,
+        report: &'a mut RunReport,
+        shutdown: &'a AtomicBool,
+        size: &Size,
+    ) -> Self {
+        // Read the weather and holidays once, for all chunks. These are extras, so if they can't
+        // be read the import continues without them.
+        let annotations = if settings.weather_path.is_some() || settings.holidays_path.is_some() {
+            report.phase("annotate");
+            let weather = match settings
+                .weather_path
+                .as_deref()
+                .map(|path| annotation::read_weather(path, &settings.locations))
+            {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    let message =
+                        format!("Could not read weather file, skipping weather annotation: {e}");
+                    error!("{message}");
+                    report.warn("annotation", None, message);
+                    Stations::default()
+                }
+                None => Stations::default(),
+            };
+            let holidays = match settings
+                .holidays_path
+                .as_deref()
+                .map(annotation::read_holidays)
+            {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    let message =
+                        format!("Could not read holidays file, skipping holiday annotation: {e}");
+                    error!("{message}");
+                    report.warn("annotation", None, message);
+                    HashMap::new()
+                }
+                None => HashMap::new(),
+            };
+            Some((weather, holidays))
+        } else {
+            None
+        };
+
+        let progress = Progress::default();
+        report.progress("deletes", &progress.deletes, size.dates);
+        report.progress(
+            "individual_inserts",
+            &progress.individual_inserts,
+            size.counts,
+        );
+        report.progress(
+            "aggregated_inserts",
+            &progress.aggregated_inserts,
+            size.daily_counts,
+        );
+
+        Self {
+            store,
+            settings,
+            report,
+            shutdown,
+            annotations,
+            tally: Tally::default(),
+            health: health::Monitor::default(),
+            latest: None,
+            progress,
+        }
+    }
+
+    // Check a chunk of counts and replace the counts for its dates with it.
+    fn load(&mut self, counts: Vec<IndividualCount>) -> Result<(), Error> {
+        // Chunks already imported are complete (and importing the file again replaces them), so a
+        // shutdown can stop the import before any chunk.
+        if self.shutdown.load(Ordering::Relaxed) {
+            return Err(Error::Shutdown);
+        }
+        let (Some(first), Some(last)) = (counts.first(), counts.last()) else {
+            return Ok(());
+        };
+        info!(
+            "Importing counts from {} to {}.",
+            first.datetime.date(),
+            last.datetime.date()
+        );
+
+        // Check each counter's channels for signs of a dead or misbehaving sensor (reported once
+        // every chunk is checked).
+        self.report.phase("health");
+        debug!("Checking health of counters.");
+        self.health.add(&counts);
+
+        // Now take these counts, and sum by date/location_id
+        self.report.phase("aggregate");
+        let daily_counts = aggregation::aggregate(&counts);
+        self.report.add_counts(&counts, &daily_counts);
+
+        // Compare daily totals to each location's history, before the records for these dates
+        // are replaced.
+        self.report.phase("anomalies");
+        debug!("Checking daily counts against historical baseline.");
+        let baselines = self.store.baselines(&daily_counts)?;
+        let anomalies = anomaly::detect(&daily_counts, &baselines, self.settings.anomaly_threshold);
+        for a in &anomalies {
+            let message = format!(
+                "Location {} on {}: total of {} deviates from baseline median of {} (robust z-score {:.1}).",
+                a.location_id, a.date, a.total, a.baseline_median, a.z
+            );
+            warn!(location_id = a.location_id; "{message}");
+            self.report.warn("anomaly", Some(a.location_id), message);
+        }
+        self.tally.add(&daily_counts, &anomalies);
+
+        // Annotate daily counts with weather and holidays.
+        let annotations = self.annotations.as_ref().map(|(weather, holidays)| {
+            self.report.phase("annotate");
+            debug!("Annotating daily counts with weather and holidays.");
+            annotation::annotate(&daily_counts, weather, holidays)
+        });
+
+        // The dates in the chunk, for deleting any existing records with those dates (to prevent
+        // adding duplicates) and replacing earlier anomalies/annotations. Deleting by date allows
+        // for far fewer deletes (one per day rather than one per record).
+        let mut dates = daily_counts.iter().map(|c| c.date).collect::<Vec<_>>();
+        dates.sort();
+        dates.dedup();
+
+        // Replace the records for each date with its counts, the deletes and inserts for a date
+        // being committed together.
+        self.report.phase("replace");
+        debug!("Replacing all existing records w/ same date in tables TBLCOUNTDATA & TBLHEADER.");
+        // Exported along with the database, once the chunk is in it.
+        let exported = self.settings.export_dir.is_some().then(|| counts.clone());
+        let replaced = self
+            .store
+            .replace_days(days(counts, daily_counts.clone()), &self.progress);
+        // Even if replacing failed, some of the dates may have been committed.
+        self.report.database_changed |= self.progress.deletes.load(Ordering::Relaxed) > 0;
+        let (from_data, from_header) = replaced?;
+        self.report.add_table_counts("TBLCOUNTDATA", from_data);
+        self.report.add_table_counts("TBLHEADER", from_header);
+
+        // Record the anomalies found earlier, replacing any for the same dates.
+        self.report.phase("record_analysis");
+        let v = self.store.replace_anomalies(&dates, &anomalies)?;
+        self.report.add_table_counts("TBLANOMALY", v);
+
+        // Record the annotations, replacing any for the same dates.
+        if let Some(annotations) = annotations {
+            let v = self.store.replace_annotations(&dates, &annotations)?;
+            self.report.add_table_counts("TBLANNOTATION", v);
+        }
+
+        if let Some(counts) = exported {
+            self.export(&counts, &daily_counts);
+        }
+
+        self.latest = dates.last().copied().max(self.latest);
+        Ok(())
+    }
+
+    // Export a chunk of counts, for use outside the database. This doesn't affect the import, so a
+    // failure is only logged.
+    fn export(&mut self, counts: &[IndividualCount], daily_counts: &[AggregatedCount]) {
+        let Some(dir) = &self.settings.export_dir else {
+            return;
+        };
+        self.report.phase("export");
+        match export::export(dir, counts, daily_counts) {
+            Ok(v) => info!("{v} files exported to {dir}."),
+            Err(e) => {
+                error!("Could not export counts to {dir}: {e}");
+                self.report
+                    .warn("export", None, format!("Could not export counts: {e}"));
+            }
+        }
+    }
+
+    // Finish up once every chunk is imported.
+    fn finish(self) {
+        for issue in self.health.finish() {
+            warn!(location_id = issue.location_id; "{issue}");
+            self.report
+                .warn("health", Some(issue.location_id), issue.to_string());
+        }
+        for location_id in self.tally.flagged_counters() {
+            let message = format!(
+                "Location {location_id}: most days deviate from baseline; check the counter."
+            );
+            warn!(location_id = location_id; "{message}");
+            self.report.warn("anomaly", Some(location_id), message);
+        }
+
+        info!("Import completed successfully.");
+
+        // Export the latest counts for the webmap. The import itself succeeded, so a failure here is
+        // only logged.
+        if let (Some(path), Some(latest)) = (&self.settings.geojson_path, self.latest) {
+            self.report.phase("geojson");
+            match self
+                .store
+                .export_geojson(&self.settings.locations, latest, path)
+            {
+                Ok(()) => info!("GeoJSON for webmap exported to {path}."),
+                Err(e) => {
+                    error!("Could not export GeoJSON for webmap: {e}");
+                    self.report
+                        .warn("geojson", None, format!("Could not export GeoJSON: {e}"));
+                }
+            }
+        }
+
+        // Compare to last year for the report. This is only informational, so a failure is
+        // logged.
+        match self.store.monthly_totals(&self.report.months()) {
+            Ok(v) => self.report.add_last_year(&v),
+            Err(e) => error!("{e}"),
+        }
+
+        // Refresh expansion factors with the newly imported data. The import itself succeeded,
+        // so a failure here is only logged.
+        self.report.phase("factors");
+        match self.store.refresh_factors(&self.settings.factor_groups) {
+            Ok(v) => {
+                info!("{} expansion factors refreshed.", v.inserted);
+                self.report.add_table_counts("TBLFACTORS", v);
+            }
+            Err(e) => {
+                error!("{e}");
+                self.report.warn("factors", None, e.to_string());
+            }
+        }
+
+        let num_deletes = self.progress.deletes.load(Ordering::Relaxed);
+        let num_individual_inserts = self.progress.individual_inserts.load(Ordering::Relaxed);
+        let num_aggregated_inserts = self.progress.aggregated_inserts.load(Ordering::Relaxed);
+        info!(count = num_deletes; "Records for {num_deletes} dates deleted.");
+        info!(count = num_individual_inserts; "{num_individual_inserts} individual counts inserted.");
+        info!(count = num_aggregated_inserts; "{num_aggregated_inserts} aggregated counts inserted.");
+    }
+}
+
+fn month(date: NaiveDate) -> (i32, u32) {
+    (date.year(), date.month())
+}
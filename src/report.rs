@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::aggregation::AggregatedCount;
+use crate::parser::IndividualCount;
+
+// Told of the phases and progress of an import as it runs (e.g. to show them in /status).
+pub trait Observer: std::fmt::Debug + Send + Sync {
+    fn start(&self, input_file: &str);
+
+    // A phase has started, or with None, the import has finished.
+    fn phase(&self, input_file: &str, name: Option<&'static str>);
+
+    // Progress of `counter` towards `total`, until the import finishes.
+    fn progress(&self, name: &'static str, counter: Arc<AtomicUsize>, total: usize);
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Running,
+    Succeeded,
+    Failed,
+    // Stopped by a shutdown before it finished; the file is imported again on restart, so this
+    // isn't a failure to alert anyone about.
+    Stopped,
+}
+
+// Rows deleted from and inserted into a table.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TableCounts {
+    pub deleted: u64,
+    pub inserted: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DateRange {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DailyTotal {
+    pub date: NaiveDate,
+    pub ped: Option<i32>,
+    pub bike: Option<i32>,
+    pub total: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MonthlyTotal {
+    pub year: i32,
+    pub month: u32,
+    pub ped: Option<i32>,
+    pub bike: Option<i32>,
+    pub total: Option<i32>,
+    // The total for the same dates in the previous year, from the database. Only the dates in the
+    // import are compared, so that an import of part of a month isn't compared to a whole month.
+    pub last_year_total: Option<i32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LocationSummary {
+    pub rows: usize,
+    pub daily_totals: Vec<DailyTotal>,
+    pub monthly_totals: Vec<MonthlyTotal>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    // What produced the warning: "health", "anomaly", "annotation", "export", etc.
+    pub kind: &'static str,
+    pub location_id: Option<i32>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Phase {
+    pub name: &'static str,
+    pub seconds: f64,
+}
+
+// A machine-readable summary of one import, written as JSON when the import finishes or fails.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub input_file: String,
+    pub started: DateTime<Local>,
+    pub finished: Option<DateTime<Local>>,
+    pub status: Status,
+    pub error: Option<String>,
+    // Whether any of the file was committed to the database, so that a failed import has left it
+    // partly imported.
+    pub database_changed: bool,
+    pub date_range: Option<DateRange>,
+    pub locations: BTreeMap<i32, LocationSummary>,
+    pub warnings: Vec<Warning>,
+    pub tables: BTreeMap<&'static str, TableCounts>,
+    pub phases: Vec<Phase>,
+    #[serde(skip)]
+    current_phase: Option<(&'static str, Instant)>,
+    // Where to publish the phase and progress while the import runs.
+    #[serde(skip)]
+    observer: Option<Arc<dyn Observer>>,
+}
+
+impl RunReport {
+    pub fn new(input_file: &str) -> Self {
+        Self {
+            input_file: input_file.to_string(),
+            started: Local::now(),
+            finished: None,
+            status: Status::Running,
+            error: None,
+            database_changed: false,
+            date_range: None,
+            locations: BTreeMap::new(),
+            warnings: vec![],
+            tables: BTreeMap::new(),
+            phases: vec![],
+            current_phase: None,
+            observer: None,
+        }
+    }
+
+    // Publish the phase and progress of the import to `observer` as it runs.
+    pub fn track(&mut self, observer: Arc<dyn Observer>) {
+        observer.start(&self.input_file);
+        self.observer = Some(observer);
+    }
+
+    // Publish the progress of `counter` towards `total`, if tracked.
+    pub fn progress(&self, name: &'static str, counter: &Arc<AtomicUsize>, total: usize) {
+        if let Some(observer) = &self.observer {
+            observer.progress(name, counter.clone(), total);
+        }
+    }
+
+    // An id for the run, from when it started (as in the name of the report file).
+    pub fn run_id(&self) -> String {
+        self.started.format("%Y%m%d-%H%M%S").to_string()
+    }
+
+    // Start timing a phase of the import, ending the timing of the previous one.
+    pub fn phase(&mut self, name: &'static str) {
+        self.end_phase();
+        self.current_phase = Some((name, Instant::now()));
+        if let Some(observer) = &self.observer {
+            observer.phase(&self.input_file, Some(name));
+        }
+    }
+
+    // An import in parts goes through the same phases for each, so the time of each phase is
+    // the total over all of them.
+    fn end_phase(&mut self) {
+        if let Some((name, start)) = self.current_phase.take() {
+            let seconds = start.elapsed().as_secs_f64();
+            match self.phases.iter_mut().find(|p| p.name == name) {
+                Some(phase) => phase.seconds += seconds,
+                None => self.phases.push(Phase { name, seconds }),
+            }
+        }
+    }
+
+    pub fn warn(&mut self, kind: &'static str, location_id: Option<i32>, message: String) {
+        self.warnings.push(Warning {
+            kind,
+            location_id,
+            message,
+        });
+    }
+
+    pub fn add_table_counts(&mut self, table: &'static str, counts: TableCounts) {
+        let entry = self.tables.entry(table).or_default();
+        entry.deleted += counts.deleted;
+        entry.inserted += counts.inserted;
+    }
+
+    // Summarize the parsed counts: the range of dates and, for each location, the number of rows
+    // and its daily and monthly totals. An import in parts adds the counts of each.
+    pub fn add_counts(&mut self, counts: &[IndividualCount], daily_counts: &[AggregatedCount]) {
+        for count in counts {
+            self.locations.entry(count.location_id).or_default().rows += 1;
+        }
+        for count in daily_counts {
+            self.locations
+                .entry(count.location_id)
+                .or_default()
+                .daily_totals
+                .push(DailyTotal {
+                    date: count.date,
+                    ped: count.total_ped,
+                    bike: count.total_bike,
+                    total: count.total,
+                });
+        }
+        for location in self.locations.values_mut() {
+            location.daily_totals.sort_by_key(|t| t.date);
+
+            let mut months: BTreeMap<(i32, u32), [Option<i32>; 3]> = BTreeMap::new();
+            for day in &location.daily_totals {
+                let month = months
+                    .entry((day.date.year(), day.date.month()))
+                    .or_default();
+                for (sum, v) in month.iter_mut().zip([day.ped, day.bike, day.total]) {
+                    if let Some(v) = v {
+                        *sum = Some(sum.unwrap_or(0) + v);
+                    }
+                }
+            }
+            location.monthly_totals = months
+                .into_iter()
+                .map(|((year, month), [ped, bike, total])| MonthlyTotal {
+                    year,
+                    month,
+                    ped,
+                    bike,
+                    total,
+                    last_year_total: None,
+                })
+                .collect();
+        }
+
+        let dates = daily_counts.iter().map(|c| c.date);
+        if let (Some(start), Some(end)) = (dates.clone().min(), dates.max()) {
+            self.date_range = Some(match self.date_range.take() {
+                Some(range) => DateRange {
+                    start: range.start.min(start),
+                    end: range.end.max(end),
+                },
+                None => DateRange { start, end },
+            });
+        }
+    }
+
+    // The first and last date in the import of each month in it.
+    pub fn months(&self) -> Vec<(NaiveDate, NaiveDate)> {
+        let mut months: BTreeMap<(i32, u32), (NaiveDate, NaiveDate)> = BTreeMap::new();
+        for day in self.locations.values().flat_map(|l| &l.daily_totals) {
+            let (first, last) = months
+                .entry((day.date.year(), day.date.month()))
+                .or_insert((day.date, day.date));
+            *first = day.date.min(*first);
+            *last = day.date.max(*last);
+        }
+        months.into_values().collect()
+    }
+
+    // Add the total of each location for the same dates in the previous year, given the totals
+    // for (location_id, year, month).
+    pub fn add_last_year(&mut self, totals: &HashMap<(i32, i32, u32), i32>) {
+        for (location_id, location) in self.locations.iter_mut() {
+            for month in location.monthly_totals.iter_mut() {
+                month.last_year_total = totals
+                    .get(&(*location_id, month.year - 1, month.month))
+                    .copied();
+            }
+        }
+    }
+
+    fn finish(&mut self, status: Status) {
+        self.end_phase();
+        if let Some(observer) = &self.observer {
+            observer.phase(&self.input_file, None);
+        }
+        self.finished = Some(Local::now());
+        self.status = status;
+    }
+
+    pub fn succeed(&mut self) {
+        self.finish(Status::Succeeded);
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        self.finish(Status::Failed);
+        self.error = Some(error.to_string());
+    }
+
+    pub fn stop(&mut self) {
+        self.finish(Status::Stopped);
+    }
+
+    // Write the report to `dir`, named by when the import started. Returns the path written to.
+    pub fn write(&self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("report-{}.json", self.run_id()));
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
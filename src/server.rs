@@ -0,0 +1,128 @@
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError};
+use log::error;
+use serde_json::json;
+use tiny_http::{Header, Response, Server};
+
+use crate::credentials::Credentials;
+use crate::db::DbConfig;
+use crate::metrics::Metrics;
+use crate::status::Tracker;
+
+// How long /health waits for the database to answer before reporting it unreachable.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What /health needs to check the database and inbox.
+pub struct Health {
+    // The directory the CSV is placed in.
+    inbox: String,
+    credentials: Credentials,
+    db: DbConfig,
+    // The database check still running, if one didn't finish in time.
+    pending: Option<Receiver<Result<(), String>>>,
+}
+
+impl Health {
+    pub fn new(inbox: String, credentials: Credentials, db: DbConfig) -> Self {
+        Self {
+            inbox,
+            credentials,
+            db,
+            pending: None,
+        }
+    }
+
+    // Check that the database is reachable and the inbox readable, returning the result of each
+    // check, and whether they all passed.
+    fn check(&mut self) -> (serde_json::Value, bool) {
+        let db = self.check_db();
+        let inbox = fs::read_dir(&self.inbox)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+        let ok = db.is_ok() && inbox.is_ok();
+        let result = |r: Result<(), String>| match r {
+            Ok(()) => "ok".to_string(),
+            Err(e) => e,
+        };
+        (
+            json!({
+                "status": if ok { "ok" } else { "error" },
+                "database": result(db),
+                "inbox": result(inbox),
+            }),
+            ok,
+        )
+    }
+
+    // Connect to the database and ping it in another thread, waiting at most DB_CHECK_TIMEOUT, so
+    // an unresponsive database can't hold up the server. A check that doesn't finish in time is
+    // left to run, and the next request waits for it rather than starting another.
+    fn check_db(&mut self) -> Result<(), String> {
+        let receiver = match self.pending.take() {
+            Some(v) => v,
+            None => {
+                let (sender, receiver) = channel::bounded(1);
+                let (db, credentials) = (self.db.clone(), self.credentials.clone());
+                thread::spawn(move || {
+                    let _ = sender.send(
+                        db.connect(&credentials)
+                            .and_then(|conn| conn.ping())
+                            .map_err(|e| e.to_string()),
+                    );
+                });
+                receiver
+            }
+        };
+        match receiver.recv_timeout(DB_CHECK_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                self.pending = Some(receiver);
+                Err(format!(
+                    "no response within {}s",
+                    DB_CHECK_TIMEOUT.as_secs()
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("check failed".to_string()),
+        }
+    }
+}
+
+// Start an HTTP server at `addr` (e.g. "0.0.0.0:9184") in a background thread, serving:
+//   - /metrics: metrics in the Prometheus text format
+//   - /health: whether the database is reachable and the inbox readable (503 if not)
+//   - /status: what the importer is doing now, and the result of the last import
+pub fn start(
+    addr: &str,
+    metrics: Arc<Metrics>,
+    tracker: Arc<Tracker>,
+    mut health: Health,
+) -> Result<(), String> {
+    let server = Server::http(addr).map_err(|e| e.to_string())?;
+    thread::spawn(move || {
+        let json_header = Header::from_bytes("Content-Type", "application/json").unwrap();
+        for request in server.incoming_requests() {
+            let response = match request.url() {
+                "/metrics" => Response::from_string(metrics.render()).with_header(
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+                ),
+                "/health" => {
+                    let (body, ok) = health.check();
+                    Response::from_string(body.to_string())
+                        .with_status_code(if ok { 200 } else { 503 })
+                        .with_header(json_header.clone())
+                }
+                "/status" => Response::from_string(tracker.to_json().to_string())
+                    .with_header(json_header.clone()),
+                _ => Response::from_string("Not found.").with_status_code(404),
+            };
+            if let Err(e) = request.respond(response) {
+                error!("Error responding to HTTP request: {e}");
+            }
+        }
+    });
+    Ok(())
+}
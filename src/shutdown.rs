@@ -0,0 +1,64 @@
+use std::env;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+// Exit codes, besides 0 for a clean shutdown and 1 for a configuration error.
+pub const EXIT_TIMEOUT: i32 = 2;
+pub const EXIT_FORCED: i32 = 3;
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 300;
+
+// The SHUTDOWN_TIMEOUT setting, in seconds (default 300).
+pub fn timeout_from_env() -> Result<u64, String> {
+    match env::var("SHUTDOWN_TIMEOUT") {
+        Ok(v) => v
+            .parse()
+            .map_err(|_| format!("SHUTDOWN_TIMEOUT must be a number of seconds, got {v}.")),
+        Err(_) => Ok(DEFAULT_TIMEOUT_SECS),
+    }
+}
+
+// Set when SIGTERM or SIGINT is received. A second signal exits immediately, with EXIT_FORCED.
+// After `timeout` seconds, the process exits with EXIT_TIMEOUT, even if an import is still in
+// progress.
+pub fn register(timeout: u64) -> io::Result<Arc<AtomicBool>> {
+    let requested = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        // The order matters: the shutdown is only conditional on the flag already being set.
+        flag::register_conditional_shutdown(signal, EXIT_FORCED, requested.clone())?;
+        flag::register(signal, requested.clone())?;
+    }
+
+    let watched = requested.clone();
+    thread::spawn(move || {
+        while !watched.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(200));
+        }
+        info!("Shutdown requested; finishing up (waiting up to {timeout}s).");
+        crate::systemd::stopping();
+        thread::sleep(Duration::from_secs(timeout));
+        error!("Did not shut down within {timeout}s; exiting. The CSV file has been kept, so it will be imported again.");
+        log::logger().flush();
+        process::exit(EXIT_TIMEOUT);
+    });
+
+    Ok(requested)
+}
+
+// Sleep for `secs`, returning early if a shutdown is requested.
+pub fn wait(requested: &AtomicBool, secs: u64) {
+    for _ in 0..secs * 5 {
+        if requested.load(Ordering::Relaxed) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
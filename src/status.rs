@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+use serde_json::{json, Value};
+
+use crate::report::{Observer, RunReport};
+use crate::{logging, systemd};
+
+// What the importer is currently doing, for /status.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    file: Option<String>,
+    started: Option<DateTime<Local>>,
+    // The phase of the import in progress, or None if idle.
+    phase: Option<&'static str>,
+    // Counters updated by the worker threads, with the number expected.
+    progress: Vec<(&'static str, Arc<AtomicUsize>, usize)>,
+    last_run: Option<Value>,
+}
+
+impl Tracker {
+    pub fn start(&self, file: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.file = Some(file.to_string());
+        inner.started = Some(Local::now());
+        inner.phase = None;
+        inner.progress.clear();
+    }
+
+    pub fn set_phase(&self, phase: &'static str) {
+        self.inner.lock().unwrap().phase = Some(phase);
+    }
+
+    // Report the progress of `counter` towards `total` until the import finishes.
+    pub fn track(&self, name: &'static str, counter: Arc<AtomicUsize>, total: usize) {
+        self.inner
+            .lock()
+            .unwrap()
+            .progress
+            .push((name, counter, total));
+    }
+
+    // The phase of the import in progress and the sum of its progress counters, which changes
+    // as long as the import is getting somewhere, or None if idle.
+    pub fn activity(&self) -> Option<(Option<&'static str>, usize)> {
+        let inner = self.inner.lock().unwrap();
+        inner.file.as_ref()?;
+        let done = inner
+            .progress
+            .iter()
+            .map(|(_, counter, _)| counter.load(Ordering::Relaxed))
+            .sum();
+        Some((inner.phase, done))
+    }
+
+    // Record the result of the import, and go back to idle.
+    pub fn finish(&self, report: &RunReport) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_run = Some(json!({
+            "file": report.input_file,
+            "started": report.started,
+            "finished": report.finished,
+            "status": report.status,
+            "error": report.error,
+            "date_range": report.date_range,
+            "warnings": report.warnings.len(),
+        }));
+        inner.file = None;
+        inner.started = None;
+        inner.phase = None;
+        inner.progress.clear();
+    }
+
+    pub fn to_json(&self) -> Value {
+        let inner = self.inner.lock().unwrap();
+        let progress = inner
+            .progress
+            .iter()
+            .map(|(name, counter, total)| {
+                (
+                    name.to_string(),
+                    json!({ "done": counter.load(Ordering::Relaxed), "total": total }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "state": match (&inner.file, inner.phase) {
+                (None, _) => "idle",
+                (Some(_), None) => "starting",
+                (Some(_), Some(phase)) => phase,
+            },
+            "file": inner.file,
+            "started": inner.started,
+            "progress": progress,
+            "last_run": inner.last_run,
+        })
+    }
+}
+
+// The phase of an import is also shown in the logs and `systemctl status`, and starting one pings
+// the watchdog.
+impl Observer for Tracker {
+    fn start(&self, input_file: &str) {
+        Tracker::start(self, input_file);
+    }
+
+    fn phase(&self, input_file: &str, name: Option<&'static str>) {
+        logging::set_phase(name);
+        if let Some(name) = name {
+            self.set_phase(name);
+            systemd::status(&format!("Importing {input_file}: {name}"));
+            systemd::watchdog();
+        }
+    }
+
+    fn progress(&self, name: &'static str, counter: Arc<AtomicUsize>, total: usize) {
+        self.track(name, counter, total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activity_changes_with_progress_and_phase() {
+        let tracker = Tracker::default();
+        assert_eq!(tracker.activity(), None);
+
+        tracker.start("export.csv");
+        let counter = Arc::new(AtomicUsize::new(0));
+        tracker.track("deletes", counter.clone(), 10);
+        tracker.set_phase("replace");
+        let before = tracker.activity();
+        assert_eq!(tracker.activity(), before);
+        counter.fetch_add(1, Ordering::Relaxed);
+        assert_ne!(tracker.activity(), before);
+        tracker.set_phase("record_analysis");
+        assert_eq!(tracker.activity(), Some((Some("record_analysis"), 1)));
+
+        tracker.finish(&RunReport::new("export.csv"));
+        assert_eq!(tracker.activity(), None);
+    }
+}
@@ -0,0 +1,700 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::thread;
+
+use chrono::prelude::*;
+use crossbeam::channel;
+use log::error;
+use oracle::sql_type::Timestamp;
+use oracle::{pool::Pool, Connection, DbError, Error as OracleError, Statement};
+
+use crate::aggregation::AggregatedCount;
+use crate::annotation::{self, Annotation};
+use crate::anomaly::{self, Anomaly, Baselines};
+use crate::db::{self, to_timestamp};
+use crate::error::Error;
+use crate::factors::{self, FactorGroup, FactorType};
+use crate::geojson::{self, GeoJsonError, Location};
+use crate::parser::IndividualCount;
+use crate::peak::PeakHour;
+use crate::report::TableCounts;
+
+// Where imported counts are stored, and the history they're checked against.
+pub trait Store {
+    // Get the historical daily totals needed to check `daily_counts` for anomalies.
+    fn baselines(&self, daily_counts: &[AggregatedCount]) -> Result<Baselines, Error>;
+
+    // Replace the individual and aggregated counts stored for each of `days` with its counts,
+    // updating `progress`, and returning the rows deleted from and inserted into TBLCOUNTDATA and
+    // TBLHEADER. Each day is replaced in one transaction, so a day that fails is left as it was.
+    fn replace_days(
+        &self,
+        days: Vec<Day>,
+        progress: &Progress,
+    ) -> Result<(TableCounts, TableCounts), Error>;
+
+    // Replace any anomalies previously recorded for `dates` with `anomalies`.
+    fn replace_anomalies(
+        &self,
+        dates: &[NaiveDate],
+        anomalies: &[Anomaly],
+    ) -> Result<TableCounts, Error>;
+
+    // Replace any annotations previously recorded for `dates` with `annotations`.
+    fn replace_annotations(
+        &self,
+        dates: &[NaiveDate],
+        annotations: &[Annotation],
+    ) -> Result<TableCounts, Error>;
+
+    // Export the counts up to `latest` for the webmap, to `path`.
+    fn export_geojson(
+        &self,
+        locations: &[Location],
+        latest: NaiveDate,
+        path: &str,
+    ) -> Result<(), GeoJsonError>;
+
+    // Get each location's total for the same dates in the previous year as each of `months`
+    // (the first and last date imported in a month), keyed by (location_id, year, month).
+    fn monthly_totals(
+        &self,
+        months: &[(NaiveDate, NaiveDate)],
+    ) -> Result<HashMap<(i32, i32, u32), i32>, Error>;
+
+    // Recalculate expansion factors from all counts.
+    fn refresh_factors(&self, groups: &[FactorGroup]) -> Result<TableCounts, Error>;
+}
+
+// The counts for one date, which replace everything stored for it.
+#[derive(Debug, Clone)]
+pub struct Day {
+    pub date: NaiveDate,
+    pub counts: Vec<IndividualCount>,
+    pub daily_counts: Vec<AggregatedCount>,
+}
+
+// Counters of dates replaced and counts inserted, for reporting progress.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub deletes: Arc<AtomicUsize>,
+    pub individual_inserts: Arc<AtomicUsize>,
+    pub aggregated_inserts: Arc<AtomicUsize>,
+}
+
+// The Oracle database, with deletes and inserts spread across `workers` threads, each with its
+// own connection from the pool.
+pub struct OracleStore<'a> {
+    pool: &'a Pool,
+    // Called when a connection can't be had from the pool (e.g. to count it).
+    on_pool_error: &'a (dyn Fn() + Sync),
+    workers: u32,
+    retries: u32,
+}
+
+impl<'a> OracleStore<'a> {
+    pub fn new(
+        pool: &'a Pool,
+        on_pool_error: &'a (dyn Fn() + Sync),
+        workers: u32,
+        retries: u32,
+    ) -> Self {
+        Self {
+            pool,
+            on_pool_error,
+            workers,
+            retries,
+        }
+    }
+
+    // Get a connection from the pool, reporting failures.
+    fn conn(&self) -> Result<Connection, OracleError> {
+        self.pool.get().inspect_err(|_| (self.on_pool_error)())
+    }
+}
+
+impl Store for OracleStore<'_> {
+    fn baselines(&self, daily_counts: &[AggregatedCount]) -> Result<Baselines, Error> {
+        // (The connection is only held for the query, as the worker threads will need them all.)
+        self.conn()
+            .and_then(|conn| anomaly::get_baselines(&conn, daily_counts))
+            .map_err(|e| Error::database("get historical baseline from TBLHEADER", e))
+    }
+
+    fn replace_days(
+        &self,
+        days: Vec<Day>,
+        progress: &Progress,
+    ) -> Result<(TableCounts, TableCounts), Error> {
+        // Number of rows inserted into TBLCOUNTDATA and TBLHEADER, if all goes well.
+        let (num_counts, num_daily_counts) = inserted(&days);
+
+        // Get a connection for each worker thread before starting any threads, so that failing to
+        // get one can't return while others are still replacing days.
+        let conns = (0..self.workers)
+            .map(|_| self.conn())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::database("get a connection from the pool", e))?;
+
+        // Create a channel to handle moving days into threads
+        let (tx, rx) = channel::unbounded();
+
+        // Create thread to send days through the channel
+        let sender_thread_handle = thread::spawn(move || {
+            for day in days {
+                match tx.send(day) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        error!("Error sending day to channel: {e}.");
+                        return;
+                    }
+                }
+            }
+        });
+
+        // Fork: spawn new threads, with each one adding a receiver, taking a day from the channel,
+        // and replacing the existing records for its date with its counts.
+        let mut receiver_thread_handles = vec![];
+        // Number of rows deleted from TBLCOUNTDATA and TBLHEADER (for reporting).
+        let num_deleted_rows = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+        for mut conn in conns {
+            let progress = progress.clone();
+            let num_deleted_rows = num_deleted_rows.clone();
+            let receiver = rx.clone();
+            let pool = self.pool.clone();
+            let retries = self.retries;
+
+            receiver_thread_handles.push(thread::spawn(move || {
+                while let Ok(day) = receiver.recv() {
+                    // Delete from TBLCOUNTDATA and TBLHEADER, insert the day's counts, and commit
+                    // them together, so that the old records are only gone once the new ones are
+                    // in. If error, retry if it may not happen again, otherwise propagate it to
+                    // main thread (the day is rolled back either way).
+                    let date = day.date.format("%d-%b-%y").to_string().to_uppercase();
+                    let (from_data, from_header) = db::with_retries(
+                        &pool,
+                        &mut conn,
+                        retries,
+                        &format!("replace records for {}", day.date),
+                        |conn| {
+                            let from_data = conn
+                                .execute(
+                                    "delete from TBLCOUNTDATA where to_char(COUNTDATE, 'DD-MON-YY')=:1",
+                                    &[&date],
+                                )?
+                                .row_count()
+                                .unwrap_or(0);
+                            let from_header = conn
+                                .execute(
+                                    "delete from TBLHEADER where to_char(COUNTDATE, 'DD-MON-YY')=:1",
+                                    &[&date],
+                                )?
+                                .row_count()
+                                .unwrap_or(0);
+                            for count in &day.counts {
+                                insert_individual_count(conn, count.clone())?;
+                            }
+                            for count in &day.daily_counts {
+                                insert_aggregated_count(conn, count.clone())?;
+                            }
+                            conn.commit()?;
+                            Ok((from_data, from_header))
+                        },
+                    )
+                    .inspect_err(|e| error!("{e}"))?;
+                    num_deleted_rows[0].fetch_add(from_data, Ordering::Relaxed);
+                    num_deleted_rows[1].fetch_add(from_header, Ordering::Relaxed);
+                    // Increment number of dates and counts (for reporting).
+                    progress.deletes.fetch_add(1, Ordering::Relaxed);
+                    progress
+                        .individual_inserts
+                        .fetch_add(day.counts.len(), Ordering::Relaxed);
+                    progress
+                        .aggregated_inserts
+                        .fetch_add(day.daily_counts.len(), Ordering::Relaxed);
+                }
+                Ok(())
+            }));
+        }
+
+        // Join: wait for sender/receiver threads to finish
+        sender_thread_handle.join().map_err(Error::panic)?;
+        join_workers(receiver_thread_handles)?;
+
+        Ok((
+            TableCounts {
+                deleted: num_deleted_rows[0].load(Ordering::Relaxed),
+                inserted: num_counts,
+            },
+            TableCounts {
+                deleted: num_deleted_rows[1].load(Ordering::Relaxed),
+                inserted: num_daily_counts,
+            },
+        ))
+    }
+
+    fn replace_anomalies(
+        &self,
+        dates: &[NaiveDate],
+        anomalies: &[Anomaly],
+    ) -> Result<TableCounts, Error> {
+        self.conn()
+            .and_then(|conn| anomaly::insert_anomalies(&conn, dates, anomalies))
+            .map_err(|e| Error::database("insert anomalies", e))
+    }
+
+    fn replace_annotations(
+        &self,
+        dates: &[NaiveDate],
+        annotations: &[Annotation],
+    ) -> Result<TableCounts, Error> {
+        self.conn()
+            .and_then(|conn| annotation::insert_annotations(&conn, dates, annotations))
+            .map_err(|e| Error::database("insert annotations", e))
+    }
+
+    fn export_geojson(
+        &self,
+        locations: &[Location],
+        latest: NaiveDate,
+        path: &str,
+    ) -> Result<(), GeoJsonError> {
+        let conn = self.conn()?;
+        geojson::export(&conn, locations, latest, path)
+    }
+
+    fn monthly_totals(
+        &self,
+        months: &[(NaiveDate, NaiveDate)],
+    ) -> Result<HashMap<(i32, i32, u32), i32>, Error> {
+        self.conn()
+            .and_then(|conn| get_monthly_totals(&conn, months))
+            .map_err(|e| Error::database("get last year's totals", e))
+    }
+
+    fn refresh_factors(&self, groups: &[FactorGroup]) -> Result<TableCounts, Error> {
+        self.conn()
+            .and_then(|conn| factors::refresh(&conn, groups))
+            .map_err(|e| Error::database("refresh expansion factors", e))
+    }
+}
+
+// An expansion factor, as stored in TBLFACTORS: (group, location_id, type, period, factor).
+pub type FactorRow = (Option<String>, Option<i32>, FactorType, u32, f64);
+
+// A store held in memory, for testing imports (and trying them out) without a database. It
+// behaves like the Oracle store, except that nothing outlives it.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    individual: Mutex<Vec<IndividualCount>>,
+    aggregated: Mutex<Vec<AggregatedCount>>,
+    anomalies: Mutex<Vec<Anomaly>>,
+    annotations: Mutex<Vec<Annotation>>,
+    factors: Mutex<Vec<FactorRow>>,
+    // A date that can't be replaced, to try out a failed import.
+    fail_on: Mutex<Option<NaiveDate>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The stored individual counts, ordered by location and time.
+    pub fn individual(&self) -> Vec<IndividualCount> {
+        let mut counts = self.individual.lock().unwrap().clone();
+        counts.sort_by_key(|c| (c.location_id, c.datetime));
+        counts
+    }
+
+    // The stored aggregated counts, ordered by location and date.
+    pub fn aggregated(&self) -> Vec<AggregatedCount> {
+        let mut counts = self.aggregated.lock().unwrap().clone();
+        counts.sort_by_key(|c| (c.location_id, c.date));
+        counts
+    }
+
+    pub fn anomalies(&self) -> Vec<Anomaly> {
+        self.anomalies.lock().unwrap().clone()
+    }
+
+    pub fn annotations(&self) -> Vec<Annotation> {
+        self.annotations.lock().unwrap().clone()
+    }
+
+    pub fn factors(&self) -> Vec<FactorRow> {
+        self.factors.lock().unwrap().clone()
+    }
+
+    // Make replacing the records for `date` fail, as if the database rejected an insert.
+    pub fn fail_on(&self, date: NaiveDate) {
+        *self.fail_on.lock().unwrap() = Some(date);
+    }
+
+    // Add counts as if from an earlier import (e.g. the history that anomalies are checked
+    // against).
+    pub fn add(&self, counts: Vec<IndividualCount>, daily_counts: Vec<AggregatedCount>) {
+        self.individual.lock().unwrap().extend(counts);
+        self.aggregated.lock().unwrap().extend(daily_counts);
+    }
+}
+
+impl Store for MemoryStore {
+    fn baselines(&self, daily_counts: &[AggregatedCount]) -> Result<Baselines, Error> {
+        let aggregated = self.aggregated.lock().unwrap();
+        let mut baselines = HashMap::new();
+        for count in daily_counts {
+            let (year, month) = (count.date.year(), count.date.month());
+            baselines
+                .entry((count.location_id, year, month))
+                .or_insert_with(|| {
+                    aggregated
+                        .iter()
+                        .filter(|c| {
+                            c.location_id == count.location_id
+                                && c.date.month() == month
+                                && c.date.year() < year
+                        })
+                        .filter_map(|c| c.total.map(|total| (c.date, total)))
+                        .collect()
+                });
+        }
+        Ok(baselines)
+    }
+
+    fn replace_days(
+        &self,
+        days: Vec<Day>,
+        progress: &Progress,
+    ) -> Result<(TableCounts, TableCounts), Error> {
+        let (num_counts, num_daily_counts) = inserted(&days);
+        let mut individual = self.individual.lock().unwrap();
+        let mut aggregated = self.aggregated.lock().unwrap();
+        let (individual_before, aggregated_before) = (individual.len(), aggregated.len());
+        // Like a rolled back transaction, a day that fails changes nothing. The other days are
+        // still replaced, as OracleStore's other worker threads keep replacing days after one
+        // fails, and the error is returned once they're done.
+        let mut failed = None;
+        for day in days {
+            if *self.fail_on.lock().unwrap() == Some(day.date) {
+                failed = Some(Error::database(
+                    format!("replace records for {}", day.date),
+                    OracleError::OciError(DbError::new(
+                        1,
+                        0,
+                        "ORA-00001: unique constraint violated".to_string(),
+                        String::new(),
+                        String::new(),
+                    )),
+                ));
+                continue;
+            }
+            individual.retain(|c| c.datetime.date() != day.date);
+            aggregated.retain(|c| c.date != day.date);
+            progress.deletes.fetch_add(1, Ordering::Relaxed);
+            progress
+                .individual_inserts
+                .fetch_add(day.counts.len(), Ordering::Relaxed);
+            progress
+                .aggregated_inserts
+                .fetch_add(day.daily_counts.len(), Ordering::Relaxed);
+            individual.extend(day.counts);
+            aggregated.extend(day.daily_counts);
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        Ok((
+            TableCounts {
+                deleted: (individual_before + num_counts as usize - individual.len()) as u64,
+                inserted: num_counts,
+            },
+            TableCounts {
+                deleted: (aggregated_before + num_daily_counts as usize - aggregated.len()) as u64,
+                inserted: num_daily_counts,
+            },
+        ))
+    }
+
+    fn replace_anomalies(
+        &self,
+        dates: &[NaiveDate],
+        anomalies: &[Anomaly],
+    ) -> Result<TableCounts, Error> {
+        let mut stored = self.anomalies.lock().unwrap();
+        let before = stored.len();
+        stored.retain(|a| !dates.contains(&a.date));
+        let deleted = (before - stored.len()) as u64;
+        stored.extend_from_slice(anomalies);
+        Ok(TableCounts {
+            deleted,
+            inserted: anomalies.len() as u64,
+        })
+    }
+
+    fn replace_annotations(
+        &self,
+        dates: &[NaiveDate],
+        annotations: &[Annotation],
+    ) -> Result<TableCounts, Error> {
+        let mut stored = self.annotations.lock().unwrap();
+        let before = stored.len();
+        stored.retain(|a| !dates.contains(&a.date));
+        let deleted = (before - stored.len()) as u64;
+        stored.extend_from_slice(annotations);
+        Ok(TableCounts {
+            deleted,
+            inserted: annotations.len() as u64,
+        })
+    }
+
+    fn export_geojson(
+        &self,
+        locations: &[Location],
+        latest: NaiveDate,
+        path: &str,
+    ) -> Result<(), GeoJsonError> {
+        let totals = self
+            .aggregated
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| (c.location_id, c.date, (c.total_ped, c.total_bike, c.total)))
+            .collect::<Vec<_>>();
+        geojson::write(&totals, locations, latest, path)
+    }
+
+    fn monthly_totals(
+        &self,
+        months: &[(NaiveDate, NaiveDate)],
+    ) -> Result<HashMap<(i32, i32, u32), i32>, Error> {
+        let mut totals = HashMap::new();
+        let aggregated = self.aggregated.lock().unwrap();
+        for (start, end) in months.iter().filter_map(|m| last_year(*m)) {
+            for count in aggregated.iter() {
+                if let (true, Some(total)) = (count.date >= start && count.date < end, count.total)
+                {
+                    *totals
+                        .entry((count.location_id, start.year(), start.month()))
+                        .or_insert(0) += total;
+                }
+            }
+        }
+        Ok(totals)
+    }
+
+    fn refresh_factors(&self, groups: &[FactorGroup]) -> Result<TableCounts, Error> {
+        // Like the Oracle store, leave out days flagged as anomalies.
+        let anomalies = self.anomalies.lock().unwrap();
+        let flagged = |c: &AggregatedCount| {
+            anomalies
+                .iter()
+                .any(|a| a.location_id == c.location_id && a.date == c.date)
+        };
+        let mut totals: HashMap<i32, Vec<(NaiveDate, i32)>> = HashMap::new();
+        for count in self
+            .aggregated
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| !flagged(c))
+        {
+            if let Some(total) = count.total {
+                totals
+                    .entry(count.location_id)
+                    .or_default()
+                    .push((count.date, total));
+            }
+        }
+        let locations = totals
+            .iter()
+            .filter_map(|(id, totals)| factors::location_factors(totals).map(|f| (*id, f)))
+            .collect::<HashMap<_, _>>();
+
+        let mut stored = self.factors.lock().unwrap();
+        let deleted = stored.len() as u64;
+        stored.clear();
+        for (location_id, factors) in &locations {
+            for ((factor_type, period), factor) in factors {
+                stored.push((None, Some(*location_id), *factor_type, *period, *factor));
+            }
+        }
+        for group in groups {
+            for ((factor_type, period), factor) in factors::group_factors(group, &locations) {
+                stored.push((Some(group.name.clone()), None, factor_type, period, factor));
+            }
+        }
+        Ok(TableCounts {
+            deleted,
+            inserted: stored.len() as u64,
+        })
+    }
+}
+
+// The number of individual and aggregated counts in `days`.
+fn inserted(days: &[Day]) -> (u64, u64) {
+    days.iter().fold((0, 0), |(counts, daily_counts), day| {
+        (
+            counts + day.counts.len() as u64,
+            daily_counts + day.daily_counts.len() as u64,
+        )
+    })
+}
+
+// Wait for the worker threads to finish, returning the first error if any failed. All of them are
+// waited for, so that nothing is still being written to the database once this returns.
+fn join_workers(handles: Vec<thread::JoinHandle<Result<(), Error>>>) -> Result<(), Error> {
+    let mut failure = None;
+    for handle in handles {
+        let result = handle.join().unwrap_or_else(|e| Err(Error::panic(e)));
+        if let Err(e) = result {
+            failure.get_or_insert(e);
+        }
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+// The same dates in the previous year as (first, last), as a start and (exclusive) end. February
+// 29 becomes February 28.
+fn last_year((first, last): (NaiveDate, NaiveDate)) -> Option<(NaiveDate, NaiveDate)> {
+    let year = chrono::Months::new(12);
+    Some((
+        first.checked_sub_months(year)?,
+        last.checked_sub_months(year)?.succ_opt()?,
+    ))
+}
+
+// Get each location's total for the same dates in the previous year as each of `months`, keyed
+// by (location_id, year, month).
+fn get_monthly_totals(
+    conn: &Connection,
+    months: &[(NaiveDate, NaiveDate)],
+) -> Result<HashMap<(i32, i32, u32), i32>, OracleError> {
+    let mut totals = HashMap::new();
+    for (start, end) in months.iter().filter_map(|m| last_year(*m)) {
+        let rows = conn.query_as::<(i32, i32)>(
+            "select locationid, sum(total) from TBLHEADER where countdate >= :1 and countdate < :2 and total is not null group by locationid",
+            &[&to_timestamp(start), &to_timestamp(end)],
+        )?;
+        for row in rows {
+            let (location_id, total) = row?;
+            totals.insert((location_id, start.year(), start.month()), total);
+        }
+    }
+    Ok(totals)
+}
+
+fn insert_individual_count(
+    conn: &Connection,
+    count: IndividualCount,
+) -> Result<Statement<'_>, OracleError> {
+    // convert datetime to date
+    // the COUNTDATE field needs to be date only, allowing the database to set the default time
+    // because existing programs rely on that to do daily/hourly aggregation
+    let oracle_date = Timestamp::new(
+        count.datetime.year(),
+        count.datetime.month(),
+        count.datetime.day(),
+        0,
+        0,
+        0,
+        0,
+    );
+
+    // COUNTTIME is ok to be full datetime
+    let oracle_dt = Timestamp::new(
+        count.datetime.year(),
+        count.datetime.month(),
+        count.datetime.day(),
+        count.datetime.hour(),
+        count.datetime.minute(),
+        count.datetime.second(),
+        0,
+    );
+
+    conn.execute("insert into TBLCOUNTDATA (locationid, countdate, total, pedin, pedout, bikein, bikeout, counttime) values (:1, :2, :3, :4, :5, :6, :7, :8)",
+        &[
+            &count.location_id,
+            &oracle_date,
+            &count.total,
+            &count.ped_in,
+            &count.ped_out,
+            &count.bike_in,
+            &count.bike_out,
+            &oracle_dt,
+        ],
+    )
+}
+
+fn insert_aggregated_count(
+    conn: &Connection,
+    count: AggregatedCount,
+) -> Result<Statement<'_>, OracleError> {
+    // convert datetime
+    let oracle_dt = to_timestamp(count.date);
+
+    // peak hours are stored as their start time along with their volumes by mode
+    let am = PeakColumns::from(&count.am_peak);
+    let pm = PeakColumns::from(&count.pm_peak);
+
+    conn.execute("insert into TBLHEADER (locationid, countdate, totalped, totalbike, total, ampeaktime, ampeaktotal, ampeakped, ampeakbike, amphf, pmpeaktime, pmpeaktotal, pmpeakped, pmpeakbike, pmphf) values (:1, :2, :3, :4, :5, :6, :7, :8, :9, :10, :11, :12, :13, :14, :15)",
+        &[
+            &count.location_id,
+            &oracle_dt,
+            &count.total_ped,
+            &count.total_bike,
+            &count.total,
+            &am.time,
+            &am.total,
+            &am.ped,
+            &am.bike,
+            &am.phf,
+            &pm.time,
+            &pm.total,
+            &pm.ped,
+            &pm.bike,
+            &pm.phf,
+        ],
+    )
+}
+
+// The peak hour columns of TBLHEADER, all NULL if there was no peak (no data in that half of the day).
+struct PeakColumns {
+    time: Option<Timestamp>,
+    total: Option<i32>,
+    ped: Option<i32>,
+    bike: Option<i32>,
+    phf: Option<f64>,
+}
+
+impl From<&Option<PeakHour>> for PeakColumns {
+    fn from(peak: &Option<PeakHour>) -> Self {
+        match peak {
+            Some(peak) => Self {
+                time: Some(Timestamp::new(
+                    peak.start.year(),
+                    peak.start.month(),
+                    peak.start.day(),
+                    peak.start.hour(),
+                    peak.start.minute(),
+                    0,
+                    0,
+                )),
+                total: peak.total,
+                ped: peak.ped,
+                bike: peak.bike,
+                phf: peak.phf,
+            },
+            None => Self {
+                time: None,
+                total: None,
+                ped: None,
+                bike: None,
+                phf: None,
+            },
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use std::thread;
+
+use log::debug;
+use sd_notify::NotifyState;
+
+use crate::status::Tracker;
+
+// Notifications to systemd, for running as a Type=notify service. Outside of systemd
+// (NOTIFY_SOCKET isn't set), these do nothing.
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(&[state]) {
+        debug!("Could not notify systemd: {e}");
+    }
+}
+
+// Startup is finished.
+pub fn ready() {
+    notify(NotifyState::Ready);
+}
+
+// Keep the watchdog from restarting the service.
+pub fn watchdog() {
+    notify(NotifyState::Watchdog);
+}
+
+// While an import runs, keep pinging the watchdog as long as it makes progress (a phase starts or
+// a date is replaced), checking at half the watchdog interval. A hung Oracle call stops the
+// progress, and so the pings. Does nothing if the watchdog isn't enabled.
+pub fn watch(tracker: Arc<Tracker>) {
+    let Some(interval) = sd_notify::watchdog_enabled() else {
+        return;
+    };
+    thread::spawn(move || {
+        let mut last = None;
+        loop {
+            thread::sleep(interval / 2);
+            let activity = tracker.activity();
+            if activity.is_some() && activity != last {
+                watchdog();
+            }
+            last = activity;
+        }
+    });
+}
+
+// Shown by `systemctl status`.
+pub fn status(status: &str) {
+    notify(NotifyState::Status(status));
+}
+
+pub fn stopping() {
+    notify(NotifyState::Stopping);
+}
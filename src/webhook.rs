@@ -0,0 +1,334 @@
+use std::env;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+use log::{error, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::report::{RunReport, Status};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_RETRIES: u32 = 3;
+// The wait before the first retry, doubled for each after it.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+// An import event, posted to each webhook as JSON. `text` is what Slack and Teams incoming
+// webhooks display; generic receivers can use `event` and `details`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub event: &'static str,
+    pub text: String,
+    pub details: Value,
+}
+
+impl Event {
+    pub fn file_detected(file: &str) -> Self {
+        Self {
+            event: "file_detected",
+            text: format!("Eco-Counter import started: {file}"),
+            details: json!({ "file": file }),
+        }
+    }
+
+    // The events for a finished import: validation warnings, if there were any, and its outcome.
+    // An import stopped by a shutdown has none, as it will be imported again.
+    pub fn from_report(report: &RunReport) -> Vec<Self> {
+        let mut events = vec![];
+        if let Status::Stopped = report.status {
+            return events;
+        }
+        if !report.warnings.is_empty() {
+            events.push(Self {
+                event: "validation_warnings",
+                text: format!(
+                    "Eco-Counter import of {} had {} validation warning(s).",
+                    report.input_file,
+                    report.warnings.len()
+                ),
+                details: json!({
+                    "file": report.input_file,
+                    "warnings": report.warnings,
+                }),
+            });
+        }
+        events.push(match report.status {
+            Status::Failed => Self {
+                event: "import_failed",
+                text: format!(
+                    "Eco-Counter import of {} failed: {}",
+                    report.input_file,
+                    report.error.as_deref().unwrap_or_default()
+                ),
+                details: json!({
+                    "file": report.input_file,
+                    "error": report.error,
+                }),
+            },
+            _ => Self {
+                event: "import_succeeded",
+                text: match &report.date_range {
+                    Some(range) => format!(
+                        "Eco-Counter import succeeded: {} to {}, {} location(s).",
+                        range.start,
+                        range.end,
+                        report.locations.len()
+                    ),
+                    None => "Eco-Counter import succeeded.".to_string(),
+                },
+                details: json!({
+                    "file": report.input_file,
+                    "date_range": report.date_range,
+                    "tables": report.tables,
+                }),
+            },
+        });
+        events
+    }
+}
+
+// Where and how to post events, before anything is started.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    urls: Vec<String>,
+    timeout: u64,
+    retries: u32,
+}
+
+impl WebhookConfig {
+    // Returns None if WEBHOOK_URLS isn't set.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(urls) = env::var("WEBHOOK_URLS") else {
+            return Ok(None);
+        };
+        let urls = urls
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>();
+        if urls.is_empty() {
+            return Ok(None);
+        }
+        let timeout = match env::var("WEBHOOK_TIMEOUT") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| format!("WEBHOOK_TIMEOUT must be a number of seconds, got {v}."))?,
+            Err(_) => DEFAULT_TIMEOUT_SECS,
+        };
+        let retries = match env::var("WEBHOOK_RETRIES") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| format!("WEBHOOK_RETRIES must be a number, got {v}."))?,
+            Err(_) => DEFAULT_RETRIES,
+        };
+        Ok(Some(Self {
+            urls,
+            timeout,
+            retries,
+        }))
+    }
+
+    // Start the sending thread.
+    pub fn start(self) -> Webhooks {
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(self.timeout)))
+            .build()
+            .into();
+        Webhooks::start(agent, self.urls, self.retries, BACKOFF)
+    }
+}
+
+// Posts events to webhooks from a background thread, so a slow or unreachable webhook never
+// holds up the import loop.
+pub struct Webhooks {
+    sender: channel::Sender<Event>,
+    thread: JoinHandle<()>,
+}
+
+impl Webhooks {
+    // Start the sending thread, posting each event to each of `urls` in turn.
+    fn start(agent: ureq::Agent, urls: Vec<String>, retries: u32, backoff: Duration) -> Self {
+        let (sender, receiver) = channel::unbounded::<Event>();
+        let thread = thread::spawn(move || {
+            for event in receiver {
+                let body = match serde_json::to_string(&event) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Could not serialize webhook event: {e}");
+                        continue;
+                    }
+                };
+                for (index, url) in urls.iter().enumerate() {
+                    post(&agent, index, url, &body, retries, backoff);
+                }
+            }
+        });
+        Self { sender, thread }
+    }
+
+    // Queue an event to be posted.
+    pub fn send(&self, event: Event) {
+        // The receiver only goes away if the sending thread panicked.
+        if self.sender.send(event).is_err() {
+            error!("Webhook thread is not running; event dropped.");
+        }
+    }
+
+    // Stop taking events, and wait up to `timeout` for those already queued to be posted, so that
+    // the events of the last import (e.g. one that failed and stopped the program) aren't lost
+    // when the program exits.
+    pub fn close(self, timeout: Duration) {
+        let Self { sender, thread } = self;
+        drop(sender);
+        let deadline = Instant::now() + timeout;
+        while !thread.is_finished() {
+            if Instant::now() >= deadline {
+                error!(
+                    "Webhook events still not posted after {}s; exiting without them.",
+                    timeout.as_secs()
+                );
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        if thread.join().is_err() {
+            error!("Webhook thread panicked.");
+        }
+    }
+}
+
+// How a webhook is named in the log: its position in WEBHOOK_URLS and its host. Webhook URLs
+// often carry a secret token in the path or query, so the URL itself is never logged.
+fn describe(index: usize, url: &str) -> String {
+    match url
+        .parse::<ureq::http::Uri>()
+        .ok()
+        .and_then(|u| u.host().map(str::to_string))
+    {
+        Some(host) => format!("webhook {} ({host})", index + 1),
+        None => format!("webhook {}", index + 1),
+    }
+}
+
+// Why a post failed, leaving out the URL that some errors quote.
+fn reason(error: &ureq::Error) -> String {
+    match error {
+        ureq::Error::BadUri(_) => "invalid URL".to_string(),
+        ureq::Error::RequireHttpsOnly(_) => "URL is not https".to_string(),
+        e => e.to_string(),
+    }
+}
+
+// Post to a webhook, retrying with exponential backoff (by default 1s, 2s, 4s, ...).
+fn post(agent: &ureq::Agent, index: usize, url: &str, body: &str, retries: u32, backoff: Duration) {
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            thread::sleep(backoff * (1 << (attempt - 1).min(6)));
+        }
+        match agent.post(url).content_type("application/json").send(body) {
+            Ok(_) => return,
+            Err(e) => warn!(
+                "Post to {} failed (attempt {}): {}",
+                describe(index, url),
+                attempt + 1,
+                reason(&e)
+            ),
+        }
+    }
+    error!(
+        "Giving up on post to {} after {} attempt(s).",
+        describe(index, url),
+        retries + 1
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhooks_are_described_without_their_url() {
+        let url = "https://hooks.slack.com/services/T000/B000/secret";
+        assert_eq!(describe(1, url), "webhook 2 (hooks.slack.com)");
+        assert_eq!(describe(0, "not a url"), "webhook 1");
+    }
+
+    #[test]
+    fn import_stopped_by_shutdown_posts_nothing() {
+        let mut report = RunReport::new("export.csv");
+        report.warn(
+            "health",
+            Some(16),
+            "Counter 16 reported nothing".to_string(),
+        );
+        report.stop();
+        assert!(Event::from_report(&report).is_empty());
+    }
+
+    #[test]
+    fn failed_posts_are_retried() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook?token=secret", server.server_addr());
+        let webhooks = Webhooks::start(
+            ureq::Agent::new_with_defaults(),
+            vec![url],
+            3,
+            Duration::from_millis(10),
+        );
+        webhooks.send(Event::file_detected("export.csv"));
+
+        // Fail the first two posts; the third gets through and no more are made.
+        let mut bodies = vec![];
+        for status in [500, 503, 200] {
+            let mut request = server
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap()
+                .unwrap();
+            assert_eq!(request.method(), &tiny_http::Method::Post);
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            bodies.push(body);
+            request.respond(tiny_http::Response::empty(status)).unwrap();
+        }
+        assert!(server
+            .recv_timeout(Duration::from_millis(200))
+            .unwrap()
+            .is_none());
+
+        assert!(bodies.iter().all(|b| *b == bodies[0]));
+        let body: Value = serde_json::from_str(&bodies[0]).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "event": "file_detected",
+                "text": "Eco-Counter import started: export.csv",
+                "details": { "file": "export.csv" },
+            })
+        );
+    }
+
+    #[test]
+    fn close_waits_for_queued_events() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+        let (posted, received) = channel::unbounded();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                posted.send(request.url().to_string()).unwrap();
+                request.respond(tiny_http::Response::empty(200)).unwrap();
+            }
+        });
+
+        let webhooks = Webhooks::start(
+            ureq::Agent::new_with_defaults(),
+            vec![url],
+            0,
+            Duration::from_millis(10),
+        );
+        webhooks.send(Event::file_detected("export.csv"));
+        webhooks.send(Event::file_detected("export.csv"));
+        webhooks.close(Duration::from_secs(5));
+        assert_eq!(received.try_iter().count(), 2);
+    }
+}
@@ -0,0 +1,720 @@
+// End-to-end tests of imports, from the fixture exports in tests/fixtures into a MemoryStore.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+
+use eco_counter_import::annotation::Annotation;
+use eco_counter_import::anomaly::{Anomaly, Baselines};
+use eco_counter_import::factors::FactorGroup;
+use eco_counter_import::geojson::{GeoJsonError, Location};
+use eco_counter_import::pipeline::Settings;
+use eco_counter_import::report::{RunReport, TableCounts};
+use eco_counter_import::store::{Day, MemoryStore, Progress, Store};
+use eco_counter_import::{aggregate, parse, pipeline, AggregatedCount, Error, IndividualCount};
+
+// 20 counters, hourly.
+const LOCATIONS: usize = 20;
+
+fn fixture(name: &str) -> File {
+    File::open(format!(
+        "{}/tests/fixtures/{name}",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap()
+}
+
+fn settings() -> Settings {
+    Settings {
+        anomaly_threshold: 3.5,
+        weather_path: None,
+        holidays_path: None,
+        factor_groups: vec![],
+        geojson_path: None,
+        locations: vec![],
+        export_dir: None,
+        chunk_days: 7,
+    }
+}
+
+fn import(name: &str, store: &MemoryStore, settings: &Settings) -> Result<RunReport, Error> {
+    let mut report = RunReport::new(name);
+    pipeline::import(
+        fixture(name),
+        store,
+        settings,
+        &mut report,
+        &AtomicBool::new(false),
+    )?;
+    Ok(report)
+}
+
+fn date(month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+}
+
+fn daily(counts: &[AggregatedCount], location_id: i32, date: NaiveDate) -> &AggregatedCount {
+    counts
+        .iter()
+        .find(|c| c.location_id == location_id && c.date == date)
+        .unwrap()
+}
+
+fn sum(counts: &[IndividualCount], f: impl Fn(&IndividualCount) -> Option<i32>) -> Option<i32> {
+    counts.iter().filter_map(f).reduce(|a, b| a + b)
+}
+
+#[test]
+fn parses_normal_month() {
+    let counts = parse(fixture("normal_month.csv")).unwrap();
+    assert_eq!(counts.len(), 30 * 24 * LOCATIONS);
+
+    let first = &counts[0];
+    assert_eq!(first.location_id, 16);
+    assert_eq!(first.datetime, date(6, 1).and_hms_opt(0, 0, 0).unwrap());
+
+    // Every counter but the one-way bike lanes has all four channels, adding up to its total.
+    for count in counts.iter().filter(|c| ![24, 25].contains(&c.location_id)) {
+        let channels = [count.ped_in, count.ped_out, count.bike_in, count.bike_out];
+        assert!(channels.iter().all(Option::is_some));
+        assert_eq!(count.total, Some(channels.iter().flatten().sum()));
+    }
+}
+
+#[test]
+fn one_way_bike_lanes_are_remapped() {
+    let counts = parse(fixture("normal_month.csv")).unwrap();
+    for count in counts.iter().filter(|c| [24, 25].contains(&c.location_id)) {
+        assert!(count.total.is_some());
+        assert_eq!(count.bike_in, count.total);
+        assert_eq!(
+            (count.bike_out, count.ped_in, count.ped_out),
+            (None, None, None)
+        );
+    }
+
+    let daily_counts = aggregate(&counts);
+    let pine = daily(&daily_counts, 24, date(6, 1));
+    assert_eq!(pine.total_bike, pine.total);
+    assert_eq!(pine.total_ped, None);
+}
+
+#[test]
+fn repeated_hour_when_clocks_go_back_is_kept() {
+    let counts = parse(fixture("dst_month.csv")).unwrap();
+    assert_eq!(counts.len(), (30 * 24 + 1) * LOCATIONS);
+
+    let bartram = counts
+        .iter()
+        .filter(|c| c.location_id == 16 && c.datetime.date() == date(11, 3))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(bartram.len(), 25);
+
+    let daily_counts = aggregate(&counts);
+    let day = daily(&daily_counts, 16, date(11, 3));
+    assert_eq!(day.total, sum(&bartram, |c| c.total));
+    assert_eq!(
+        day.total_ped,
+        sum(&bartram, |c| Some(c.ped_in? + c.ped_out?))
+    );
+}
+
+#[test]
+fn repeated_hour_is_not_one_peak_window() {
+    // Make both 1 AMs busier than any other hour, so that together they'd be the AM peak.
+    let mut counts = parse(fixture("dst_month.csv")).unwrap();
+    for count in counts
+        .iter_mut()
+        .filter(|c| c.location_id == 16 && c.datetime.date() == date(11, 3))
+        .filter(|c| c.datetime.hour() == 1)
+    {
+        count.total = Some(500);
+    }
+
+    let daily_counts = aggregate(&counts);
+    let am_peak = daily(&daily_counts, 16, date(11, 3))
+        .am_peak
+        .clone()
+        .unwrap();
+    assert_eq!(am_peak.start, date(11, 3).and_hms_opt(1, 0, 0).unwrap());
+    assert_eq!(am_peak.total, Some(500));
+}
+
+#[test]
+fn missing_columns_are_a_parse_error() {
+    assert!(matches!(
+        parse(fixture("missing_columns.csv")),
+        Err(Error::Parse(_))
+    ));
+}
+
+#[test]
+fn short_row_is_a_validation_error() {
+    match parse(fixture("short_row.csv")) {
+        Err(Error::Validation(message)) => assert!(message.contains("found 92"), "{message}"),
+        other => panic!("expected a validation error, got {other:?}"),
+    }
+}
+
+#[test]
+fn quirky_counters_have_missing_data_as_none() {
+    let counts = parse(fixture("quirky_counters.csv")).unwrap();
+    let daily_counts = aggregate(&counts);
+    let at = |location_id| {
+        counts
+            .iter()
+            .filter(|c| c.location_id == location_id && c.datetime.date() == date(6, 15))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    // Offline for the whole day.
+    let cooper = daily(&daily_counts, 11, date(6, 15));
+    assert_eq!(
+        (cooper.total_ped, cooper.total_bike, cooper.total),
+        (None, None, None)
+    );
+
+    // No pedestrian OUT data, so the pedestrian total is only IN.
+    let darby = daily(&daily_counts, 12, date(6, 15));
+    assert_eq!(darby.total_ped, sum(&at(12), |c| c.ped_in));
+    assert!(at(12).iter().all(|c| c.ped_out.is_none()));
+
+    // No bicycle data for the first six hours, which are skipped rather than counted as 0.
+    let monroe = at(10);
+    assert!(monroe[..6].iter().all(|c| c.bike_in.is_none()));
+    let day = daily(&daily_counts, 10, date(6, 15));
+    assert_eq!(
+        day.total_bike,
+        sum(&monroe, |c| Some(c.bike_in? + c.bike_out?))
+    );
+
+    // No totals, but the channels are still summed.
+    let port_richmond = daily(&daily_counts, 7, date(6, 15));
+    assert_eq!(port_richmond.total, None);
+    assert_eq!(
+        port_richmond.total_ped,
+        sum(&at(7), |c| Some(c.ped_in? + c.ped_out?))
+    );
+}
+
+#[test]
+fn imports_into_store() {
+    let store = MemoryStore::new();
+    let report = import("normal_month.csv", &store, &settings()).unwrap();
+
+    assert_eq!(store.individual().len(), 30 * 24 * LOCATIONS);
+    assert_eq!(store.aggregated().len(), 30 * LOCATIONS);
+    assert_eq!(store.aggregated(), {
+        let mut expected = aggregate(&parse(fixture("normal_month.csv")).unwrap());
+        expected.sort_by_key(|c| (c.location_id, c.date));
+        expected
+    });
+    assert_eq!(report.tables["TBLCOUNTDATA"].inserted, 14_400);
+    assert_eq!(report.tables["TBLCOUNTDATA"].deleted, 0);
+    assert_eq!(report.tables["TBLHEADER"].inserted, 600);
+}
+
+#[test]
+fn importing_again_replaces_the_same_dates() {
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &settings()).unwrap();
+    let report = import("normal_month.csv", &store, &settings()).unwrap();
+    assert_eq!(report.tables["TBLCOUNTDATA"].deleted, 14_400);
+    assert_eq!(store.individual().len(), 14_400);
+
+    // Only the two days in this export are replaced.
+    let report = import("quirky_counters.csv", &store, &settings()).unwrap();
+    assert_eq!(report.tables["TBLCOUNTDATA"].deleted, 2 * 24 * 20);
+    assert_eq!(store.individual().len(), 14_400);
+    assert_eq!(store.aggregated().len(), 600);
+    let cooper = store.aggregated();
+    assert_eq!(daily(&cooper, 11, date(6, 15)).total, None);
+    assert!(daily(&cooper, 11, date(6, 14)).total.is_some());
+}
+
+// A temporary directory for exports, removed when dropped.
+struct ExportDir(std::path::PathBuf);
+
+impl ExportDir {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("eco-counter-import-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        Self(dir)
+    }
+
+    fn settings(&self) -> Settings {
+        let mut settings = settings();
+        settings.export_dir = Some(self.0.to_string_lossy().into_owned());
+        settings
+    }
+
+    // The rows of location 16's daily totals in the CSV export for a month of 2024.
+    fn bartram_totals(&self, month: u32) -> Vec<String> {
+        fs::read_to_string(
+            self.0
+                .join(format!("csv/year=2024/month={month:02}/daily.csv")),
+        )
+        .unwrap()
+        .lines()
+        .filter(|l| l.starts_with("16,") && l.contains(",total,"))
+        .map(String::from)
+        .collect()
+    }
+
+    // The number of rows in a location's Parquet export of daily counts for a month of 2024.
+    fn daily_parquet_rows(&self, month: u32, location_id: i32) -> i64 {
+        let path = self.0.join(format!(
+            "parquet/daily/year=2024/month={month:02}/location={location_id}/counts.parquet"
+        ));
+        SerializedFileReader::new(File::open(path).unwrap())
+            .unwrap()
+            .metadata()
+            .file_metadata()
+            .num_rows()
+    }
+}
+
+impl Drop for ExportDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+#[test]
+fn exporting_some_days_keeps_the_rest_of_the_month() {
+    let dir = ExportDir::new("partial-month");
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &dir.settings()).unwrap();
+    let before = dir.bartram_totals(6);
+    import("quirky_counters.csv", &store, &dir.settings()).unwrap();
+
+    let after = dir.bartram_totals(6);
+    assert_eq!(after.len(), 30);
+    for day in 1..=30 {
+        assert!(after[day - 1].starts_with(&format!("16,2024-06-{day:02},")));
+    }
+    // Only the two days in the second export changed.
+    let changed = before.iter().zip(&after).filter(|(a, b)| a != b).count();
+    assert!(changed <= 2);
+    assert_eq!(dir.daily_parquet_rows(6, 16), 30);
+
+    // Cooper was offline for both days of the second export, which now have no total, but the rest
+    // of the month still does.
+    let cooper = fs::read_to_string(dir.0.join("csv/year=2024/month=06/daily.csv"))
+        .unwrap()
+        .lines()
+        .filter(|l| l.starts_with("11,") && l.contains(",total,"))
+        .count();
+    assert_eq!(cooper, 28);
+}
+
+#[test]
+fn failed_insert_leaves_the_date_as_it_was() {
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &settings()).unwrap();
+    let before = store.aggregated();
+
+    // Jun 15 is replaced, and Jun 16 fails after its old records would have been deleted.
+    store.fail_on(date(6, 16));
+    let mut report = RunReport::new("quirky_counters.csv");
+    let result = pipeline::import(
+        fixture("quirky_counters.csv"),
+        &store,
+        &settings(),
+        &mut report,
+        &AtomicBool::new(false),
+    );
+    assert!(matches!(result, Err(Error::Database { .. })));
+    // So the file isn't quarantined with only part of it imported.
+    assert!(report.database_changed);
+
+    let after = store.aggregated();
+    assert_eq!(daily(&after, 11, date(6, 15)).total, None);
+    assert_eq!(
+        daily(&after, 11, date(6, 16)),
+        daily(&before, 11, date(6, 16))
+    );
+    assert_eq!(
+        store
+            .individual()
+            .iter()
+            .filter(|c| c.datetime.date() == date(6, 16))
+            .count(),
+        24 * LOCATIONS
+    );
+    assert_eq!(after.len(), before.len());
+}
+
+#[test]
+fn dates_after_a_failed_one_are_still_replaced() {
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &settings()).unwrap();
+    let before = store.aggregated();
+
+    // Jun 15 fails, but, as with the database's other worker threads, Jun 16 is replaced anyway.
+    store.fail_on(date(6, 15));
+    let mut report = RunReport::new("quirky_counters.csv");
+    let result = pipeline::import(
+        fixture("quirky_counters.csv"),
+        &store,
+        &settings(),
+        &mut report,
+        &AtomicBool::new(false),
+    );
+    assert!(matches!(result, Err(Error::Database { .. })));
+    assert!(report.database_changed);
+
+    let after = store.aggregated();
+    assert_eq!(
+        daily(&after, 11, date(6, 15)),
+        daily(&before, 11, date(6, 15))
+    );
+    assert_eq!(daily(&after, 11, date(6, 16)).total, None);
+    assert_eq!(after.len(), before.len());
+}
+
+#[test]
+fn failed_parse_leaves_store_unchanged() {
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &settings()).unwrap();
+    assert!(matches!(
+        import("short_row.csv", &store, &settings()),
+        Err(Error::Validation(_))
+    ));
+    assert_eq!(store.individual().len(), 14_400);
+}
+
+#[test]
+fn shutdown_before_changes_stops_import() {
+    let store = MemoryStore::new();
+    let mut report = RunReport::new("normal_month.csv");
+    let result = pipeline::import(
+        fixture("normal_month.csv"),
+        &store,
+        &settings(),
+        &mut report,
+        &AtomicBool::new(true),
+    );
+    assert!(matches!(result, Err(Error::Shutdown)));
+    assert!(store.individual().is_empty());
+}
+
+#[test]
+fn exports_geojson_for_webmap() {
+    let path =
+        std::env::temp_dir().join(format!("eco-counter-import-{}.geojson", std::process::id()));
+    let mut settings = settings();
+    settings.geojson_path = Some(path.to_string_lossy().into_owned());
+    settings.locations = vec![Location {
+        location_id: 16,
+        name: "Bartram's Garden".to_string(),
+        latitude: 39.93,
+        longitude: -75.21,
+    }];
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &settings).unwrap();
+
+    let geojson: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    fs::remove_file(&path).ok();
+    let properties = &geojson["features"][0]["properties"];
+    assert_eq!(properties["locationid"], 16);
+    assert_eq!(properties["daily"].as_array().unwrap().len(), 30);
+    let june = &properties["monthly"][0];
+    assert_eq!(june["month"], "2024-06");
+    let total = store
+        .aggregated()
+        .iter()
+        .filter(|c| c.location_id == 16)
+        .filter_map(|c| c.total)
+        .sum::<i32>();
+    assert_eq!(june["total"], total);
+}
+
+// A MemoryStore that records the dates of each chunk of counts replaced, and can
+// request a shutdown once a number of chunks are in.
+struct ChunkRecorder<'a> {
+    store: MemoryStore,
+    chunks: Mutex<Vec<Vec<NaiveDate>>>,
+    shutdown_after: Option<(usize, &'a AtomicBool)>,
+}
+
+impl<'a> ChunkRecorder<'a> {
+    fn new(shutdown_after: Option<(usize, &'a AtomicBool)>) -> Self {
+        Self {
+            store: MemoryStore::new(),
+            chunks: Mutex::new(vec![]),
+            shutdown_after,
+        }
+    }
+}
+
+impl Store for ChunkRecorder<'_> {
+    fn baselines(&self, daily_counts: &[AggregatedCount]) -> Result<Baselines, Error> {
+        self.store.baselines(daily_counts)
+    }
+
+    fn replace_days(
+        &self,
+        days: Vec<Day>,
+        progress: &Progress,
+    ) -> Result<(TableCounts, TableCounts), Error> {
+        let dates = days.iter().map(|d| d.date).collect();
+        let replaced = self.store.replace_days(days, progress)?;
+        let mut chunks = self.chunks.lock().unwrap();
+        chunks.push(dates);
+        if let Some((n, shutdown)) = self.shutdown_after {
+            if chunks.len() >= n {
+                shutdown.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(replaced)
+    }
+
+    fn replace_anomalies(
+        &self,
+        dates: &[NaiveDate],
+        anomalies: &[Anomaly],
+    ) -> Result<TableCounts, Error> {
+        self.store.replace_anomalies(dates, anomalies)
+    }
+
+    fn replace_annotations(
+        &self,
+        dates: &[NaiveDate],
+        annotations: &[Annotation],
+    ) -> Result<TableCounts, Error> {
+        self.store.replace_annotations(dates, annotations)
+    }
+
+    fn export_geojson(
+        &self,
+        locations: &[Location],
+        latest: NaiveDate,
+        path: &str,
+    ) -> Result<(), GeoJsonError> {
+        self.store.export_geojson(locations, latest, path)
+    }
+
+    fn monthly_totals(
+        &self,
+        months: &[(NaiveDate, NaiveDate)],
+    ) -> Result<HashMap<(i32, i32, u32), i32>, Error> {
+        self.store.monthly_totals(months)
+    }
+
+    fn refresh_factors(&self, groups: &[FactorGroup]) -> Result<TableCounts, Error> {
+        self.store.refresh_factors(groups)
+    }
+}
+
+// The normal month followed by the DST month, as one export of two months.
+fn two_months() -> Cursor<Vec<u8>> {
+    let june = fs::read_to_string(format!(
+        "{}/tests/fixtures/normal_month.csv",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap();
+    let november = fs::read_to_string(format!(
+        "{}/tests/fixtures/dst_month.csv",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap();
+    let rows = november.lines().skip(2).collect::<Vec<_>>().join("\n");
+    Cursor::new(format!("{june}{rows}\n").into_bytes())
+}
+
+#[test]
+fn imports_in_chunks_of_days_within_a_month() {
+    let store = ChunkRecorder::new(None);
+    let mut report = RunReport::new("two_months.csv");
+    pipeline::import(
+        two_months(),
+        &store,
+        &settings(),
+        &mut report,
+        &AtomicBool::new(false),
+    )
+    .unwrap();
+
+    let chunks = store.chunks.lock().unwrap();
+    // June: 4 weeks and 2 days; November: 4 weeks and 2 days.
+    assert_eq!(chunks.len(), 10);
+    for chunk in chunks.iter() {
+        assert!(chunk.len() <= 7);
+        assert_eq!(
+            chunk[chunk.len() - 1] - chunk[0],
+            chrono::Duration::days(chunk.len() as i64 - 1)
+        );
+        assert_eq!(chunk[0].month(), chunk[chunk.len() - 1].month());
+    }
+    assert_eq!(chunks[4], [date(6, 29), date(6, 30)]);
+    assert_eq!(chunks[5][0], date(11, 1));
+
+    assert_eq!(store.store.individual().len(), (60 * 24 + 1) * LOCATIONS);
+    assert_eq!(
+        report.tables["TBLCOUNTDATA"].inserted,
+        (60 * 24 + 1) as u64 * 20
+    );
+    let range = report.date_range.as_ref().unwrap();
+    assert_eq!((range.start, range.end), (date(6, 1), date(11, 30)));
+    // Each phase is reported once, with its time over all chunks.
+    let mut phases = report.phases.iter().map(|p| p.name).collect::<Vec<_>>();
+    phases.sort();
+    phases.dedup();
+    assert_eq!(phases.len(), report.phases.len());
+}
+
+#[test]
+fn chunk_size_does_not_change_result() {
+    let mut results = vec![];
+    for chunk_days in [1, 7, 31] {
+        let store = MemoryStore::new();
+        let mut settings = settings();
+        settings.chunk_days = chunk_days;
+        let mut report = RunReport::new("two_months.csv");
+        pipeline::import(
+            two_months(),
+            &store,
+            &settings,
+            &mut report,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        results.push((store.individual(), store.aggregated()));
+    }
+    assert_eq!(results[0], results[1]);
+    assert_eq!(results[1], results[2]);
+}
+
+#[test]
+fn rows_out_of_order_change_nothing() {
+    let mut rows = fs::read_to_string(format!(
+        "{}/tests/fixtures/normal_month.csv",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap()
+    .lines()
+    .map(String::from)
+    .collect::<Vec<_>>();
+    // Move the last day's midnight to the start of the file.
+    let last = rows.len() - 24;
+    let row = rows.remove(last);
+    rows.insert(2, row);
+
+    let store = MemoryStore::new();
+    let result = pipeline::import(
+        Cursor::new(rows.join("\n").into_bytes()),
+        &store,
+        &settings(),
+        &mut RunReport::new("out_of_order.csv"),
+        &AtomicBool::new(false),
+    );
+    match result {
+        Err(Error::Validation(message)) => assert!(message.contains("out of order"), "{message}"),
+        other => panic!("expected a validation error, got {other:?}"),
+    }
+    assert!(store.individual().is_empty());
+}
+
+#[test]
+fn shutdown_stops_between_chunks() {
+    let shutdown = AtomicBool::new(false);
+    let store = ChunkRecorder::new(Some((2, &shutdown)));
+    let result = pipeline::import(
+        fixture("normal_month.csv"),
+        &store,
+        &settings(),
+        &mut RunReport::new("normal_month.csv"),
+        &shutdown,
+    );
+    assert!(matches!(result, Err(Error::Shutdown)));
+    // The two chunks imported are complete.
+    assert_eq!(store.store.individual().len(), 14 * 24 * LOCATIONS);
+    assert_eq!(store.store.aggregated().len(), 14 * LOCATIONS);
+}
+
+#[test]
+fn exports_each_chunk_as_it_is_imported() {
+    let dir = ExportDir::new("chunks");
+    let store = MemoryStore::new();
+    pipeline::import(
+        two_months(),
+        &store,
+        &dir.settings(),
+        &mut RunReport::new("two_months.csv"),
+        &AtomicBool::new(false),
+    )
+    .unwrap();
+
+    for month in [6, 11] {
+        assert_eq!(dir.bartram_totals(month).len(), 30);
+        assert_eq!(dir.daily_parquet_rows(month, 16), 30);
+    }
+}
+
+#[test]
+fn export_starting_mid_month_keeps_the_days_before_it() {
+    let dir = ExportDir::new("mid-month");
+    let store = MemoryStore::new();
+    import("normal_month.csv", &store, &dir.settings()).unwrap();
+
+    // June 10 onward, which is imported (and exported) in chunks starting on the 10th and 17th.
+    let june = fs::read_to_string(format!(
+        "{}/tests/fixtures/normal_month.csv",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap();
+    let lines = june.lines().collect::<Vec<_>>();
+    let rows = [&lines[..2], &lines[2 + 9 * 24..]].concat().join("\n");
+    let report = {
+        let mut report = RunReport::new("june_10_on.csv");
+        pipeline::import(
+            Cursor::new(rows.into_bytes()),
+            &store,
+            &dir.settings(),
+            &mut report,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        report
+    };
+    assert_eq!(
+        report.date_range.as_ref().map(|r| r.start),
+        Some(date(6, 10))
+    );
+
+    assert_eq!(dir.bartram_totals(6).len(), 30);
+    assert_eq!(dir.daily_parquet_rows(6, 16), 30);
+}
+
+#[test]
+fn part_of_a_month_is_compared_to_the_same_days_last_year() {
+    let store = MemoryStore::new();
+    let last_year = aggregate(&parse(fixture("normal_month.csv")).unwrap())
+        .into_iter()
+        .map(|mut c| {
+            c.date = c.date.with_year(2023).unwrap();
+            c
+        })
+        .collect::<Vec<_>>();
+    let expected = last_year
+        .iter()
+        .filter(|c| c.location_id == 16 && (15..=16).contains(&c.date.day()))
+        .filter_map(|c| c.total)
+        .sum::<i32>();
+    store.add(vec![], last_year);
+
+    // The export only has Jun 15–16, so the rest of June 2023 isn't counted.
+    let report = import("quirky_counters.csv", &store, &settings()).unwrap();
+    let june = &report.locations[&16].monthly_totals[0];
+    assert_eq!((june.year, june.month), (2024, 6));
+    assert_eq!(june.last_year_total, Some(expected));
+}